@@ -23,7 +23,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         group.throughput(Throughput::Bytes(exp.len() as u64));
         group.sample_size(150);
         group.bench_function(name, |c| {
-            c.iter(|| black_box(Exp::from_str(&exp)));
+            c.iter(|| black_box(Exp::from_str(exp)));
         });
     }
 
@@ -94,6 +94,21 @@ pub fn criterion_benchmark(c: &mut Criterion) {
             });
         });
     }
+
+    {
+        let program = pre_processor.compile("pbr/pbr.wgsl");
+        let defines = pre_processor.defines.clone();
+
+        let mut group = c.benchmark_group("Program::process");
+        group.sample_size(150);
+        group.bench_function("pbr.wgsl", |c| {
+            c.iter(|| {
+                program.process(&defines, |line| {
+                    black_box(line);
+                });
+            });
+        });
+    }
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,23 @@
+//! Diffs the SIMD expression parser against the portable reference parser and checks that
+//! every valid expression survives a round trip through `Display`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use preproc::exp::Exp;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(data) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let exp = preproc::parse_exp(data).ok();
+    let reference = preproc::scalar::parse_exp(data).ok();
+    assert_eq!(exp, reference, "{:?}", data);
+
+    if let Some(exp) = exp {
+        let text = exp.to_string();
+        let parsed = Exp::from_str(&text).expect("failed to parse a displayed expression");
+        assert_eq!(exp, parsed, "{:?} was displayed as {:?}", data, &text);
+    }
+});
@@ -0,0 +1,28 @@
+//! Diffs the `Line`s of the SIMD parser against the ones of the portable reference parser
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use preproc::Line;
+use preproc_fuzz::{catch, config, init};
+
+fuzz_target!(init: init(), |data: &[u8]| {
+    let (config, data) = config(data);
+    let Ok(data) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let lines = catch(|| {
+        let mut lines: Vec<Line> = vec![];
+        preproc::parse_file(data, &config, |line| lines.push(line));
+        lines
+    });
+
+    let reference = catch(|| {
+        let mut lines: Vec<Line> = vec![];
+        preproc::scalar::parse_file(data, &config, |line| lines.push(line));
+        lines
+    });
+
+    assert_eq!(lines, reference, "{:?}", data);
+});
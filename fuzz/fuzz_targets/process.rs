@@ -0,0 +1,35 @@
+//! Processes a set of in memory files and diffs the output against a straightforward
+//! implementation built on top of the portable reference parser
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use preproc::PreProcessor;
+use preproc_fuzz::{catch, config, files, init, process};
+
+fuzz_target!(init: init(), |data: &[u8]| {
+    let (config, data) = config(data);
+    let Ok(data) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let (defines, loader, Some(root)) = files(data) else {
+        return;
+    };
+
+    let reference = catch(|| process(&config, &loader, &defines, &root));
+
+    let output = catch(move || {
+        let mut pre_processor = PreProcessor::with_loader(loader);
+        pre_processor.config = config;
+        for def in &defines {
+            pre_processor.defines.insert(def.as_str().into());
+        }
+
+        let mut output = String::new();
+        pre_processor.process_to_str(&root, &mut output);
+        output
+    });
+
+    assert_eq!(output, reference, "{:?}", data);
+});
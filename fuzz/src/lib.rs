@@ -0,0 +1,201 @@
+//! Shared helpers for the fuzz targets
+
+use std::{cell::Cell, panic};
+
+use hashbrown::HashSet;
+use preproc::{exp::Ctx, Config, Directive, Line, MemoryFileLoader};
+
+thread_local! {
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Installs a panic hook that lets the panics raised inside [`catch`] unwind,
+/// any other panic still aborts the process using the libfuzzer hook
+pub fn init() {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !CATCHING.with(Cell::get) {
+            hook(info);
+        }
+    }));
+}
+
+/// Runs `f` returning `None` if it panicked, the parsers report malformed inputs by panicking
+pub fn catch<T>(f: impl FnOnce() -> T) -> Option<T> {
+    CATCHING.with(|catching| catching.set(true));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(f)).ok();
+    CATCHING.with(|catching| catching.set(false));
+    result
+}
+
+/// Uses the first byte of the input to pick a [`Config`], so the fuzzer can explore
+/// other special chars and comment styles
+pub fn config(data: &[u8]) -> (Config, &[u8]) {
+    let Some((&first, data)) = data.split_first() else {
+        return (Config::default(), data);
+    };
+
+    let mut config = Config {
+        special_char: [b'#', b'@', b'%', b'!'][(first & 0b11) as usize],
+        comment: ["//", "#", "--", ";"][((first >> 2) & 0b11) as usize].into(),
+        ..Default::default()
+    };
+    if first & 0b1_0000 != 0 {
+        config.include_begin = b'<';
+        config.include_end = b'>';
+    }
+    match first >> 5 {
+        0b001 => config.nested_comments = true,
+        0b010 => config.comment_begin.clear(),
+        0b011 => {
+            config.comment_begin = "{-".into();
+            config.comment_end = "-}".into();
+        }
+        0b100 => config.string_delimiters.clear(),
+        0b101 => {
+            config.string_delimiters = "'`".into();
+            config.string_escape = None;
+        }
+        0b110 => {
+            config.directives.push(("elseif".into(), Directive::Elif));
+            config.directives.push(("elsif".into(), Directive::Elif));
+            config
+                .directives
+                .push(("import".into(), Directive::Include));
+        }
+        _ => {}
+    }
+
+    (config, data)
+}
+
+/// Splits the input into the user defines and a set of files, segments are separated by `'\0'`,
+/// the first one is a white space separated list of defines and each of the following
+/// segments is a file starting with its name followed by a new line
+///
+/// The first file is the root
+pub fn files(input: &str) -> (HashSet<String>, MemoryFileLoader, Option<String>) {
+    let mut segments = input.split('\0');
+
+    let defines = segments
+        .next()
+        .unwrap_or_default()
+        .split_ascii_whitespace()
+        .map(String::from)
+        .collect();
+
+    let mut loader = MemoryFileLoader::default();
+    let mut root = None;
+    for segment in segments {
+        let (name, data) = segment.split_once('\n').unwrap_or((segment, ""));
+        root.get_or_insert_with(|| name.to_string());
+        loader.files.insert(name.to_string(), data.to_string());
+    }
+
+    (defines, loader, root)
+}
+
+#[derive(Clone, Copy)]
+struct Branch {
+    active: bool,
+    taken: bool,
+    has_else: bool,
+}
+
+/// Straightforward implementation of `PreProcessor::process` on top of [`preproc::scalar`],
+/// each line outputted is followed by `'\n'`
+pub fn process(
+    config: &Config,
+    loader: &MemoryFileLoader,
+    defines: &HashSet<String>,
+    root: &str,
+) -> String {
+    fn recurse(
+        config: &Config,
+        loader: &MemoryFileLoader,
+        path: &str,
+        ctx: &mut Ctx,
+        includes: &mut Vec<String>,
+        output: &mut String,
+    ) {
+        let data = loader.files.get(path).expect("file not found");
+
+        let mut lines = vec![];
+        preproc::scalar::parse_file(data, config, |line| lines.push(line));
+
+        let mut branches: Vec<Branch> = vec![];
+        let active = |branches: &[Branch]| branches.last().is_none_or(|branch| branch.active);
+
+        for line in &lines {
+            match line {
+                Line::Code(text)
+                | Line::Block { code: text, .. }
+                | Line::Rem(text)
+                | Line::Unknown { line: text, .. } => {
+                    if active(&branches) {
+                        output.push_str(text);
+                        output.push('\n');
+                    }
+                }
+                Line::Inc(inc) => {
+                    if active(&branches) {
+                        assert!(
+                            !includes.iter().any(|path| path == inc),
+                            "recursive include"
+                        );
+                        includes.push(inc.to_string());
+                        recurse(config, loader, inc, ctx, includes, output);
+                        includes.pop();
+                    }
+                }
+                Line::Def(def) => {
+                    if active(&branches) {
+                        ctx.vars.insert((*def).into());
+                    }
+                }
+                Line::Undef(def) => {
+                    if active(&branches) {
+                        ctx.vars.remove(*def);
+                    }
+                }
+                Line::If(exp) => {
+                    let parent = active(&branches);
+                    let value = parent && exp.eval(ctx);
+                    branches.push(Branch {
+                        active: value,
+                        taken: value || !parent,
+                        has_else: false,
+                    });
+                }
+                Line::Elif(exp) => {
+                    let branch = branches.last_mut().expect("`elif` without `if`");
+                    assert!(!branch.has_else, "`elif` after `else`");
+                    branch.active = !branch.taken && exp.eval(ctx);
+                    branch.taken |= branch.active;
+                }
+                Line::Else => {
+                    let branch = branches.last_mut().expect("`else` without `if`");
+                    assert!(!branch.has_else, "`else` after `else`");
+                    branch.active = !branch.taken;
+                    branch.taken = true;
+                    branch.has_else = true;
+                }
+                Line::Endif => {
+                    branches.pop().expect("`endif` without `if`");
+                }
+            }
+        }
+
+        assert!(branches.is_empty(), "`if` block left open");
+    }
+
+    let mut ctx = Ctx::default();
+    for def in defines {
+        ctx.vars.insert(def.as_str().into());
+    }
+
+    let mut output = String::new();
+    let mut includes = vec![root.to_string()];
+    recurse(config, loader, root, &mut ctx, &mut includes, &mut output);
+    output
+}
@@ -0,0 +1,866 @@
+//! Language server for the pre-processor directives, speaks LSP over stdio
+//!
+//! Settings are read from the `initializationOptions` or from the `preproc` section of
+//! `workspace/didChangeConfiguration`:
+//!
+//! - `searchPaths`: directories where includes are searched, relative to the workspace root
+//! - `defines`: defines used to find the inactive regions
+//!
+//! Inactive regions are sent with the `textDocument/inactiveRegions` notification,
+//! the same one used by clangd
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use preproc::{DefineSet, File, FileLoader, Line, PreProcessor};
+use serde_json::{json, Value};
+
+/// Text of the open documents by path, they take precedence over the files in the disk
+type Documents = Arc<Mutex<HashMap<String, String>>>;
+
+struct Loader {
+    documents: Documents,
+    search_paths: Vec<String>,
+}
+
+impl Loader {
+    /// Finds `path` in the open documents or in the disk, directly or inside a search path
+    fn find(&self, path: &str) -> Option<(String, Option<String>)> {
+        let documents = self.documents.lock().unwrap();
+        let candidates = std::iter::once(String::from(path)).chain(
+            self.search_paths
+                .iter()
+                .map(|search_path| join(search_path, path)),
+        );
+        for candidate in candidates {
+            if let Some(text) = documents.get(&candidate) {
+                return Some((candidate, Some(text.clone())));
+            }
+            if Path::new(&candidate).is_file() {
+                return Some((candidate, None));
+            }
+        }
+        None
+    }
+}
+
+impl FileLoader for Loader {
+    fn load(&self, path: &str) -> Option<String> {
+        match self.find(path)? {
+            (_, Some(text)) => Some(text),
+            (path, None) => std::fs::read_to_string(path).ok(),
+        }
+    }
+
+    fn resolve(&self, path: &str) -> Option<String> {
+        self.find(path).map(|(path, _)| path)
+    }
+}
+
+struct Server {
+    documents: Documents,
+    /// Workspace root, search paths are relative to it
+    root: String,
+    search_paths: Vec<String>,
+    defines: DefineSet,
+    pre_processor: PreProcessor,
+    shutdown: bool,
+    exit: bool,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        let documents = Documents::default();
+        Self {
+            pre_processor: PreProcessor::with_loader(Loader {
+                documents: documents.clone(),
+                search_paths: vec![],
+            }),
+            documents,
+            root: String::new(),
+            search_paths: vec![],
+            defines: DefineSet::default(),
+            shutdown: false,
+            exit: false,
+        }
+    }
+}
+
+impl Server {
+    /// Handles a request or notification, returns the messages to send back
+    fn handle(&mut self, message: Value) -> Vec<Value> {
+        let id = message.get("id").cloned();
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+
+        let mut messages = vec![];
+        let result = match method {
+            "initialize" => {
+                if let Some(root) = params["rootUri"].as_str() {
+                    self.root = uri_to_path(root);
+                }
+                self.configure(&params["initializationOptions"]);
+                Some(json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "definitionProvider": true,
+                        "hoverProvider": true,
+                        "completionProvider": { "triggerCharacters": [" ", "(", "!"] },
+                    },
+                    "serverInfo": { "name": "preproc-lsp", "version": env!("CARGO_PKG_VERSION") },
+                }))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Value::Null)
+            }
+            "exit" => {
+                self.exit = true;
+                None
+            }
+            "textDocument/didOpen" => {
+                let path = uri_to_path(params["textDocument"]["uri"].as_str().unwrap_or_default());
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents
+                    .lock()
+                    .unwrap()
+                    .insert(path.clone(), text.into());
+                self.invalidate(&path);
+                self.publish(&mut messages);
+                None
+            }
+            "textDocument/didChange" => {
+                let path = uri_to_path(params["textDocument"]["uri"].as_str().unwrap_or_default());
+                // full sync, the last change has the whole text
+                if let Some(text) = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                {
+                    self.documents
+                        .lock()
+                        .unwrap()
+                        .insert(path.clone(), text.into());
+                }
+                self.invalidate(&path);
+                self.publish(&mut messages);
+                None
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let path = uri_to_path(uri);
+                self.documents.lock().unwrap().remove(&path);
+                // the file in the disk is used again
+                self.invalidate(&path);
+                messages.push(notification(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                ));
+                self.publish(&mut messages);
+                None
+            }
+            "workspace/didChangeConfiguration" => {
+                let settings = &params["settings"];
+                self.configure(settings.get("preproc").unwrap_or(settings));
+                // includes can resolve to other files with new search paths
+                self.pre_processor.invalidate_all();
+                self.publish(&mut messages);
+                None
+            }
+            "textDocument/definition" => Some(self.definition(params)),
+            "textDocument/hover" => Some(self.hover(params)),
+            "textDocument/completion" => Some(self.completion(params)),
+            _ => {
+                // notifications that aren't supported are ignored
+                if let Some(id) = &id {
+                    messages.push(json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": -32601, "message": format!("unknown method `{}`", method) },
+                    }));
+                }
+                None
+            }
+        };
+
+        if let (Some(id), Some(result)) = (id, result) {
+            messages.insert(0, json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+        }
+        messages
+    }
+
+    fn configure(&mut self, settings: &Value) {
+        if let Some(search_paths) = settings["searchPaths"].as_array() {
+            self.search_paths = search_paths
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|path| join(&self.root, path))
+                .collect();
+        }
+        if let Some(defines) = settings["defines"].as_array() {
+            self.defines = defines
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|def| def.into())
+                .collect();
+        }
+
+        let mut search_paths = self.search_paths.clone();
+        if !self.root.is_empty() {
+            search_paths.push(self.root.clone());
+        }
+        self.pre_processor.file_loader = Box::new(Loader {
+            documents: self.documents.clone(),
+            search_paths,
+        });
+    }
+
+    /// Removes the cached files that resolve to the document `path`, the files including
+    /// it are kept since they are evaluated again with the new text anyway
+    fn invalidate(&mut self, path: &str) {
+        let pre_processor = &mut self.pre_processor;
+        let stale: Vec<String> = pre_processor
+            .files
+            .keys()
+            .filter(|key| {
+                *key == path || pre_processor.file_loader.resolve(key).as_deref() == Some(path)
+            })
+            .cloned()
+            .collect();
+        for key in stale {
+            pre_processor.invalidate(&key);
+        }
+    }
+
+    /// Sends the diagnostics and inactive regions of every open document
+    fn publish(&mut self, messages: &mut Vec<Value>) {
+        let mut documents: Vec<(String, String)> = self
+            .documents
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, text)| (path.clone(), text.clone()))
+            .collect();
+        documents.sort();
+
+        for (path, text) in documents {
+            let uri = path_to_uri(&path);
+            let diagnostics = self.diagnostics(&text);
+            let regions = if diagnostics.is_empty() {
+                self.inactive_regions(&path)
+            } else {
+                vec![]
+            };
+
+            messages.push(notification(
+                "textDocument/publishDiagnostics",
+                json!({ "uri": uri, "diagnostics": diagnostics }),
+            ));
+            messages.push(notification(
+                "textDocument/inactiveRegions",
+                json!({ "textDocument": { "uri": uri }, "regions": regions }),
+            ));
+        }
+    }
+
+    /// Bad expressions, unbalanced `#if` blocks and includes that can't be found
+    fn diagnostics(&self, text: &str) -> Vec<Value> {
+        let lines: Vec<&str> = text.lines().collect();
+        let diagnostic = |line: usize, message: String| {
+            let len = lines.get(line - 1).map_or(0, |text| text.chars().count());
+            json!({
+                "range": range(line - 1, 0, line - 1, len),
+                "severity": 1,
+                "source": "preproc",
+                "message": message,
+            })
+        };
+
+        let config = &self.pre_processor.config;
+        let file = match catch(|| File::parse(text.into(), config)) {
+            Ok(file) => file,
+            Err(message) => {
+                // parse errors end with `line:column`
+                let line = message
+                    .rsplit_once(' ')
+                    .and_then(|(_, position)| position.split_once(':'))
+                    .and_then(|(line, _)| line.parse().ok())
+                    .unwrap_or(1);
+                return vec![diagnostic(line, message)];
+            }
+        };
+
+        let mut diagnostics = vec![];
+        // line of each open `#if` and if it already has an `#else`
+        let mut chains: Vec<(usize, bool)> = vec![];
+        for (line, entry) in file.numbered_lines() {
+            match entry {
+                Line::If(_) => chains.push((line, false)),
+                Line::Elif(_) | Line::Else => {
+                    let directive = if let Line::Else = entry {
+                        "else"
+                    } else {
+                        "elif"
+                    };
+                    match chains.last_mut() {
+                        None => diagnostics.push(diagnostic(
+                            line,
+                            format!("`{}` doesn't have a maching `if`", directive),
+                        )),
+                        Some((_, true)) => diagnostics
+                            .push(diagnostic(line, format!("`{}` after `else`", directive))),
+                        Some((_, has_else)) => *has_else = matches!(entry, Line::Else),
+                    }
+                }
+                Line::Endif if chains.pop().is_none() => {
+                    diagnostics.push(diagnostic(
+                        line,
+                        "`endif` doesn't have a maching `if`".into(),
+                    ));
+                }
+                Line::Inc(inc) if self.pre_processor.file_loader.resolve(inc).is_none() => {
+                    diagnostics.push(diagnostic(
+                        line,
+                        format!("couldn't find include file \"{}\"", inc),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        for (line, _) in chains {
+            diagnostics.push(diagnostic(
+                line,
+                "`if` doesn't have a maching `endif`".into(),
+            ));
+        }
+        diagnostics
+    }
+
+    /// Ranges of the lines removed with the current defines
+    fn inactive_regions(&mut self, path: &str) -> Vec<Value> {
+        let pre_processor = &mut self.pre_processor;
+        let defines = &self.defines;
+        let Ok(ranges) = catch(|| pre_processor.inactive_ranges(path, defines)) else {
+            return vec![];
+        };
+        let Some(file) = self.pre_processor.files.get(path) else {
+            return vec![];
+        };
+
+        let lines: Vec<&str> = file.data().lines().collect();
+        ranges
+            .into_iter()
+            .map(|(start, end)| {
+                let len = lines.get(end - 1).map_or(0, |line| line.chars().count());
+                range(start - 1, 0, end - 1, len)
+            })
+            .collect()
+    }
+
+    fn definition(&mut self, params: &Value) -> Value {
+        let Some((path, line, _)) = self.position(params) else {
+            return Value::Null;
+        };
+        let Some(file) = self.pre_processor.preload(&path) else {
+            return Value::Null;
+        };
+
+        for (number, entry) in file.numbered_lines() {
+            if let &Line::Inc(inc) = entry {
+                if number == line + 1 {
+                    return match self.pre_processor.file_loader.resolve(inc) {
+                        Some(path) => json!({
+                            "uri": path_to_uri(&path),
+                            "range": range(0, 0, 0, 0),
+                        }),
+                        None => Value::Null,
+                    };
+                }
+            }
+        }
+        Value::Null
+    }
+
+    /// Shows where the define under the cursor is set
+    fn hover(&mut self, params: &Value) -> Value {
+        let Some((path, line, character)) = self.position(params) else {
+            return Value::Null;
+        };
+        let Some(word) = self
+            .line(&path, line)
+            .and_then(|text| word_at(&text, character))
+        else {
+            return Value::Null;
+        };
+
+        let pre_processor = &mut self.pre_processor;
+        let Ok(graph) = catch(|| pre_processor.include_graph(&[&path])) else {
+            return Value::Null;
+        };
+
+        let mut sites = String::new();
+        let mut used = false;
+        // the cache also has the files of other documents
+        let mut file_paths = graph.dependencies_of(&path);
+        file_paths.insert(0, &path);
+        for file_path in file_paths {
+            let Some(file) = self.pre_processor.files.get(file_path) else {
+                continue;
+            };
+            let resolved = self
+                .pre_processor
+                .file_loader
+                .resolve(file_path)
+                .unwrap_or_else(|| file_path.into());
+            for (number, entry) in file.numbered_lines() {
+                match entry {
+                    &Line::Def(def) | &Line::Undef(def) if def == word => {
+                        let directive = if let Line::Def(_) = entry {
+                            "define"
+                        } else {
+                            "undef"
+                        };
+                        sites.push_str(&format!("- `#{}` at {}:{}\n", directive, resolved, number));
+                    }
+                    Line::If(exp) | Line::Elif(exp) => {
+                        used |= exp
+                            .ops
+                            .iter()
+                            .any(|op| matches!(op, preproc::exp::Op::Var(var) if *var == word));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if sites.is_empty() && !used {
+            return Value::Null;
+        }
+
+        let mut markdown = format!("**{}**\n\n", word);
+        if self.defines.contains(word.as_str()) {
+            markdown.push_str("Defined by the settings\n\n");
+        }
+        if sites.is_empty() {
+            markdown.push_str("Not set by any file");
+        } else {
+            markdown.push_str(&sites);
+        }
+        json!({ "contents": { "kind": "markdown", "value": markdown } })
+    }
+
+    /// Directive keywords right after the special char, defines in the rest of a directive
+    fn completion(&mut self, params: &Value) -> Value {
+        let Some((path, line, character)) = self.position(params) else {
+            return Value::Null;
+        };
+        let Some(text) = self.line(&path, line) else {
+            return Value::Null;
+        };
+        let before: String = text.chars().take(character).collect();
+        let Some(directive) = before
+            .trim_start()
+            .strip_prefix(self.pre_processor.config.special_char as char)
+        else {
+            return json!([]);
+        };
+
+        if directive
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            let items: Vec<Value> = self
+                .pre_processor
+                .config
+                .directives
+                .iter()
+                .map(|(keyword, _)| json!({ "label": keyword.as_str(), "kind": 14 }))
+                .collect();
+            return json!(items);
+        }
+
+        let mut defines = DefineSet::default();
+        let pre_processor = &mut self.pre_processor;
+        let _ = catch(|| pre_processor.find_defines_of(&path, &mut defines));
+        defines.extend(self.defines.iter().cloned());
+
+        let mut defines: Vec<&str> = defines.iter().map(|def| def.as_str()).collect();
+        defines.sort();
+        let items: Vec<Value> = defines
+            .into_iter()
+            .map(|def| json!({ "label": def, "kind": 21 }))
+            .collect();
+        json!(items)
+    }
+
+    /// Path, line and character of a `TextDocumentPositionParams`
+    fn position(&self, params: &Value) -> Option<(String, usize, usize)> {
+        let path = uri_to_path(params["textDocument"]["uri"].as_str()?);
+        let line = params["position"]["line"].as_u64()? as usize;
+        let character = params["position"]["character"].as_u64()? as usize;
+        Some((path, line, character))
+    }
+
+    fn line(&self, path: &str, line: usize) -> Option<String> {
+        let text = self.pre_processor.file_loader.load(path)?;
+        text.lines().nth(line).map(String::from)
+    }
+}
+
+/// Identifier at `character`
+fn word_at(text: &str, character: usize) -> Option<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let is_word = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+    let mut start = character.min(chars.len());
+    while start > 0 && is_word(&chars[start - 1]) {
+        start -= 1;
+    }
+    let end = start + chars[start..].iter().take_while(|c| is_word(c)).count();
+    (end > start).then(|| chars[start..end].iter().collect())
+}
+
+fn range(start_line: usize, start: usize, end_line: usize, end: usize) -> Value {
+    json!({
+        "start": { "line": start_line, "character": start },
+        "end": { "line": end_line, "character": end },
+    })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn join(base: &str, path: &str) -> String {
+    if base.is_empty() || Path::new(path).is_absolute() {
+        return path.into();
+    }
+    Path::new(base).join(path).to_string_lossy().into_owned()
+}
+
+/// Runs `f` returning the panic message as the error, the pre-processor reports errors
+/// by panicking
+fn catch<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|err| {
+        err.downcast_ref::<String>()
+            .cloned()
+            .or_else(|| err.downcast_ref::<&str>().map(|err| err.to_string()))
+            .unwrap_or_else(|| "unknown error".into())
+    })
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = vec![];
+    let mut iter = path.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex: Vec<u8> = iter.by_ref().take(2).collect();
+            if let Some(byte) = std::str::from_utf8(&hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                bytes.push(byte);
+                continue;
+            }
+        }
+        bytes.push(byte);
+    }
+    let path = String::from_utf8_lossy(&bytes).into_owned();
+
+    // `file:///C:/dir` on windows
+    match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => path[1..].into(),
+        _ => path,
+    }
+}
+
+fn path_to_uri(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~:".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let length = length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+fn main() -> io::Result<()> {
+    // errors are reported as diagnostics
+    std::panic::set_hook(Box::new(|_| {}));
+
+    let mut server = Server::default();
+    let mut reader = io::stdin().lock();
+    let mut writer = io::stdout().lock();
+    while let Some(message) = read_message(&mut reader)? {
+        for message in server.handle(message) {
+            write_message(&mut writer, &message)?;
+        }
+        if server.exit {
+            break;
+        }
+    }
+
+    if !server.shutdown {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn request(id: u64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    fn position(path: &str, line: usize, character: usize) -> Value {
+        json!({
+            "textDocument": { "uri": path_to_uri(path) },
+            "position": { "line": line, "character": character },
+        })
+    }
+
+    fn open(server: &mut Server, path: &str, text: &str) -> Vec<Value> {
+        server.handle(notification(
+            "textDocument/didOpen",
+            json!({ "textDocument": { "uri": path_to_uri(path), "text": text } }),
+        ))
+    }
+
+    /// Params of the last notification `method` sent to `path`
+    fn find<'a>(messages: &'a [Value], method: &str, path: &str) -> &'a Value {
+        let uri = path_to_uri(path);
+        &messages
+            .iter()
+            .rev()
+            .find(|message| {
+                message["method"] == method
+                    && (message["params"]["uri"] == uri.as_str()
+                        || message["params"]["textDocument"]["uri"] == uri.as_str())
+            })
+            .expect("notification not sent")["params"]
+    }
+
+    #[test]
+    fn session() {
+        let mut server = Server::default();
+        let response = server.handle(request(
+            1,
+            "initialize",
+            json!({
+                "rootUri": "file:///work",
+                "initializationOptions": { "searchPaths": ["shaders"], "defines": ["B"] },
+            }),
+        ));
+        assert_eq!(response[0]["result"]["capabilities"]["hoverProvider"], true);
+
+        open(&mut server, "/work/shaders/inc.wgsl", "#define A\ninc");
+        let messages = open(
+            &mut server,
+            "/work/main.wgsl",
+            "#include \"inc.wgsl\"\n#if A && !B\nab\n\nmore\n#else\nelse\n#endif\n#if C\n#endif",
+        );
+        let diagnostics = &find(
+            &messages,
+            "textDocument/publishDiagnostics",
+            "/work/main.wgsl",
+        );
+        assert_eq!(diagnostics["diagnostics"], json!([]));
+        let regions = &find(&messages, "textDocument/inactiveRegions", "/work/main.wgsl");
+        assert_eq!(regions["regions"], json!([range(2, 0, 4, 4)]));
+
+        // go to the included file
+        let response = server.handle(request(
+            2,
+            "textDocument/definition",
+            position("/work/main.wgsl", 0, 3),
+        ));
+        assert_eq!(
+            response[0]["result"]["uri"],
+            "file:///work/shaders/inc.wgsl"
+        );
+
+        let response = server.handle(request(
+            3,
+            "textDocument/hover",
+            position("/work/main.wgsl", 1, 4),
+        ));
+        assert_eq!(
+            response[0]["result"]["contents"]["value"],
+            "**A**\n\n- `#define` at /work/shaders/inc.wgsl:1\n"
+        );
+
+        let response = server.handle(request(
+            4,
+            "textDocument/completion",
+            position("/work/main.wgsl", 1, 4),
+        ));
+        let labels: Vec<&str> = response[0]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect();
+        assert_eq!(labels, ["A", "B", "C"]);
+
+        // new define set
+        let messages = server.handle(notification(
+            "workspace/didChangeConfiguration",
+            json!({ "settings": { "preproc": { "defines": [] } } }),
+        ));
+        let regions = &find(&messages, "textDocument/inactiveRegions", "/work/main.wgsl");
+        assert_eq!(regions["regions"], json!([range(6, 0, 6, 4)]));
+
+        let messages = open(
+            &mut server,
+            "/work/bad.wgsl",
+            "#if A\n#else\n#elif B\n#endif\n#endif\n#include \"missing.wgsl\"\n#if A",
+        );
+        let diagnostics = &find(
+            &messages,
+            "textDocument/publishDiagnostics",
+            "/work/bad.wgsl",
+        );
+        let diagnostics: Vec<(u64, &str)> = diagnostics["diagnostics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|diagnostic| {
+                (
+                    diagnostic["range"]["start"]["line"].as_u64().unwrap(),
+                    diagnostic["message"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                (2, "`elif` after `else`"),
+                (4, "`endif` doesn't have a maching `if`"),
+                (5, "couldn't find include file \"missing.wgsl\""),
+                (6, "`if` doesn't have a maching `endif`"),
+            ]
+        );
+
+        let messages = open(&mut server, "/work/exp.wgsl", "a\n#if (A\n#endif");
+        let diagnostics = &find(
+            &messages,
+            "textDocument/publishDiagnostics",
+            "/work/exp.wgsl",
+        );
+        assert_eq!(diagnostics["diagnostics"][0]["range"]["start"]["line"], 1);
+
+        // only the changed document is loaded again
+        let main = server.pre_processor.files["/work/main.wgsl"].clone();
+        let messages = server.handle(notification(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": path_to_uri("/work/shaders/inc.wgsl") },
+                "contentChanges": [{ "text": "inc" }],
+            }),
+        ));
+        let regions = &find(&messages, "textDocument/inactiveRegions", "/work/main.wgsl");
+        assert_eq!(regions["regions"], json!([range(2, 0, 4, 4)]));
+        assert!(Rc::ptr_eq(
+            &main,
+            &server.pre_processor.files["/work/main.wgsl"]
+        ));
+
+        let response = server.handle(request(5, "shutdown", Value::Null));
+        assert_eq!(response[0]["result"], Value::Null);
+        server.handle(notification("exit", Value::Null));
+        assert!(server.shutdown && server.exit);
+    }
+
+    #[test]
+    fn self_include() {
+        let mut server = Server::default();
+        server.handle(request(
+            1,
+            "initialize",
+            json!({ "rootUri": "file:///work" }),
+        ));
+
+        open(
+            &mut server,
+            "/work/main.wgsl",
+            "#include \"main.wgsl\"\n#if A\n#endif\n#if B\n#endif",
+        );
+        let response = server.handle(request(
+            2,
+            "textDocument/completion",
+            position("/work/main.wgsl", 3, 4),
+        ));
+        let labels: Vec<&str> = response[0]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect();
+        assert_eq!(labels, ["A", "B"]);
+    }
+
+    #[test]
+    fn transport() {
+        let message = request(1, "initialize", json!({}));
+        let mut buffer = vec![];
+        write_message(&mut buffer, &message).unwrap();
+        write_message(&mut buffer, &message).unwrap();
+
+        let mut reader = io::Cursor::new(buffer);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message.clone()));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn uris() {
+        assert_eq!(uri_to_path("file:///a%20b/c.wgsl"), "/a b/c.wgsl");
+        assert_eq!(uri_to_path("file:///C:/a.wgsl"), "C:/a.wgsl");
+        assert_eq!(path_to_uri("/a b/c.wgsl"), "file:///a%20b/c.wgsl");
+        assert_eq!(path_to_uri("C:\\a.wgsl"), "file:///C:/a.wgsl");
+    }
+}
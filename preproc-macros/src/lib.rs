@@ -0,0 +1,252 @@
+//! Compile time pre-processing, see [`preproc!`]
+
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+};
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+use preproc::{FileLoader, PreProcessor};
+
+/// Pre-processes a file at compile time and expands to its output as a `&'static str`
+///
+/// The path is relative to the `CARGO_MANIFEST_DIR` of the crate calling the macro,
+/// followed by the defines, errors are reported with the file and line like
+/// [`PreProcessor::process`] does
+///
+/// ```ignore
+/// const PBR: &str = preproc_macros::preproc!("shaders/pbr.wgsl", SKINNED, VERTEX_UVS);
+/// ```
+///
+/// Every file reached is also passed to `include_bytes!` so the crate is rebuilt
+/// when any of them changes
+#[proc_macro]
+pub fn preproc(input: TokenStream) -> TokenStream {
+    let (path, defines, span) = match parse(input) {
+        Ok(input) => input,
+        Err((message, span)) => return compile_error(&message, span),
+    };
+
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    match preprocess(manifest_dir.into(), &path, &defines) {
+        Ok((output, dependencies)) => expand(&output, &dependencies),
+        Err(message) => compile_error(&message, span),
+    }
+}
+
+/// Loads the files relative to the crate calling the macro
+struct ManifestFileLoader(PathBuf);
+
+impl FileLoader for ManifestFileLoader {
+    fn load(&self, path: &str) -> Option<String> {
+        // only a missing file is reported as not found by the pre-processor
+        let data = match std::fs::read(self.0.join(path)) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(err) => panic!("can't read file \"{}\": {}", path, err),
+        };
+        match String::from_utf8(data) {
+            Ok(data) => Some(data),
+            Err(_) => panic!("file \"{}\" isn't valid UTF-8", path),
+        }
+    }
+
+    fn resolve(&self, path: &str) -> Option<String> {
+        Some(self.0.join(path).to_string_lossy().into_owned())
+    }
+}
+
+/// Processes `path` and returns the output along with the files reached, panics of
+/// the pre-processor become errors
+fn preprocess(
+    manifest_dir: PathBuf,
+    path: &str,
+    defines: &[String],
+) -> Result<(String, Vec<String>), String> {
+    let mut pre_processor = PreProcessor::with_loader(ManifestFileLoader(manifest_dir));
+    for def in defines {
+        pre_processor.defines.insert(def.as_str().into());
+    }
+
+    // the message is reported by `compile_error!`, so the panic hook is silenced while
+    // processing and restored right after since it's shared with the whole compiler
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let mut output = String::new();
+        pre_processor.process_to_str(path, &mut output);
+        output
+    }));
+    std::panic::set_hook(hook);
+
+    match result {
+        Ok(output) => Ok((output, pre_processor.dependencies())),
+        Err(err) => Err(err
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| err.downcast_ref::<&str>().map(|err| err.to_string()))
+            .unwrap_or_else(|| "unknown error".into())),
+    }
+}
+
+type Error = (String, Span);
+
+fn parse(input: TokenStream) -> Result<(String, Vec<String>, Span), Error> {
+    let mut tokens = input.into_iter();
+
+    let (path, span) = match tokens.next() {
+        Some(TokenTree::Literal(literal)) => {
+            let span = literal.span();
+            match string_literal(&literal.to_string()) {
+                Some(path) => (path, span),
+                None => return Err(("expected a string literal with the file path".into(), span)),
+            }
+        }
+        token => {
+            let span = token.map_or_else(Span::call_site, |token| token.span());
+            return Err(("expected a string literal with the file path".into(), span));
+        }
+    };
+
+    let mut defines = vec![];
+    loop {
+        match tokens.next() {
+            None => break,
+            Some(TokenTree::Punct(punct)) if punct.as_char() == ',' => {}
+            Some(token) => return Err(("expected `,`".into(), token.span())),
+        }
+        match tokens.next() {
+            // trailing comma
+            None => break,
+            Some(TokenTree::Ident(ident)) => defines.push(ident.to_string()),
+            Some(token) => return Err(("expected a define name".into(), token.span())),
+        }
+    }
+
+    Ok((path, defines, span))
+}
+
+/// Value of a string literal as written in the source, `None` if it isn't a string literal
+fn string_literal(literal: &str) -> Option<String> {
+    if let Some(raw) = literal.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let raw = &raw[hashes..raw.len().checked_sub(hashes)?];
+        return raw.strip_prefix('"')?.strip_suffix('"').map(String::from);
+    }
+
+    let literal = literal.strip_prefix('"')?.strip_suffix('"')?;
+    let mut value = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            '0' => value.push('\0'),
+            c @ ('\\' | '"' | '\'') => value.push(c),
+            'x' => {
+                let code: String = chars.by_ref().take(2).collect();
+                value.push(u8::from_str_radix(&code, 16).ok()? as char);
+            }
+            'u' => {
+                let code: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                value.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+            }
+            '\n' => {
+                // line continuation skips the leading white spaces of the next line
+                let rest = chars.as_str().trim_start();
+                chars = rest.chars();
+            }
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// `{ const _: &[u8] = include_bytes!("dependency"); ... "output" }`
+fn expand(output: &str, dependencies: &[String]) -> TokenStream {
+    let mut block = TokenStream::new();
+    for dependency in dependencies {
+        let include: TokenStream = [
+            TokenTree::Ident(Ident::new("include_bytes", Span::call_site())),
+            TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+            TokenTree::Group(Group::new(
+                Delimiter::Parenthesis,
+                TokenTree::Literal(Literal::string(dependency)).into(),
+            )),
+        ]
+        .into_iter()
+        .collect();
+
+        let item: TokenStream = "const _: &[u8] =".parse().unwrap();
+        block.extend(item);
+        block.extend(include);
+        block.extend([TokenTree::Punct(Punct::new(';', Spacing::Alone))]);
+    }
+    block.extend([TokenTree::Literal(Literal::string(output))]);
+
+    TokenTree::Group(Group::new(Delimiter::Brace, block)).into()
+}
+
+/// `compile_error!("message")` pointing at `span`
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    [
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenTree::Literal(Literal::string(message)).into(),
+        )),
+    ]
+    .into_iter()
+    .map(|mut token| {
+        token.set_span(span);
+        token
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert_eq!(string_literal(r#""a/b.wgsl""#).as_deref(), Some("a/b.wgsl"));
+        assert_eq!(
+            string_literal(r#""a\\b \"c\" \x41\u{42}""#).as_deref(),
+            Some("a\\b \"c\" AB")
+        );
+        assert_eq!(string_literal(r###"r#"a\b"#"###).as_deref(), Some("a\\b"));
+        assert_eq!(string_literal("'a'"), None);
+        assert_eq!(string_literal("42"), None);
+    }
+
+    #[test]
+    fn preprocess_files() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("..");
+
+        let (output, dependencies) = preprocess(root.clone(), "benches/files/main.c", &[]).unwrap();
+        assert_eq!(output, "");
+        assert_eq!(dependencies.len(), 1);
+
+        let err = preprocess(root, "benches/files/missing.c", &[]).unwrap_err();
+        assert_eq!(err, "file \"benches/files/missing.c\" not found");
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let dir = std::env::temp_dir().join(format!("preproc-macros-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("latin1.wgsl"), b"// caf\xe9\n").unwrap();
+
+        let err = preprocess(dir.clone(), "latin1.wgsl", &[]).unwrap_err();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(err, "file \"latin1.wgsl\" isn't valid UTF-8");
+    }
+}
@@ -0,0 +1,12 @@
+use preproc_macros::preproc;
+
+#[test]
+fn expands_to_output() {
+    const SHADER: &str = preproc!("tests/shaders/main.wgsl");
+    assert_eq!(SHADER, "common\nstatic\n");
+
+    assert_eq!(
+        preproc!("tests/shaders/main.wgsl", SKINNED, VERTEX_UVS,),
+        "common\nskinned\n"
+    );
+}
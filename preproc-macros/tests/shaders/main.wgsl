@@ -0,0 +1,6 @@
+#include "tests/shaders/common.wgsl"
+#if SKINNED
+skinned
+#else
+static
+#endif
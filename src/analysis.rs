@@ -0,0 +1,305 @@
+//! Static analysis of which defines can change the output of a file
+
+use alloc::vec::Vec;
+
+use hashbrown::{HashMap, HashSet};
+use smartstring::{Compact, SmartString};
+
+use crate::{
+    exp::{Exp, Op},
+    File, Line, PreProcessor,
+};
+
+/// The define might be defined by a `#define`
+const DEFINED: u8 = 0b001;
+/// The define might be undefined by a `#undef`
+const UNDEFINED: u8 = 0b010;
+/// The define might still hold the value given by the user
+const EXTERNAL: u8 = 0b100;
+
+/// What each define might be at some point of the file, missing defines are [`EXTERNAL`]
+#[derive(Clone, Default)]
+struct Vars(HashMap<SmartString<Compact>, u8>);
+
+impl Vars {
+    fn new() -> Self {
+        let mut vars = Self::default();
+        // same constants as `Ctx`, "0" and "false" are expected to never be defined
+        vars.0.insert("true".into(), DEFINED);
+        vars.0.insert("1".into(), DEFINED);
+        vars.0.insert("0".into(), UNDEFINED);
+        vars.0.insert("false".into(), UNDEFINED);
+        vars
+    }
+
+    #[inline]
+    fn get(&self, var: &str) -> u8 {
+        self.0.get(var).copied().unwrap_or(EXTERNAL)
+    }
+
+    /// Merges the states of two branches
+    fn union(&mut self, other: &Vars) {
+        for (var, flags) in &mut self.0 {
+            *flags |= other.get(var);
+        }
+        for (var, &flags) in &other.0 {
+            if !self.0.contains_key(var) {
+                self.0.insert(var.clone(), flags | EXTERNAL);
+            }
+        }
+    }
+
+    /// Evaluates `exp` using three valued logic, `None` means it depends on the user defines
+    /// or on a previous condition
+    fn eval(&self, exp: &Exp) -> Option<bool> {
+        let mut stack: Vec<Option<bool>> = Vec::with_capacity(8);
+        for op in &exp.ops {
+            let value = match op {
+                Op::Var(var) => match self.get(var) {
+                    DEFINED => Some(true),
+                    UNDEFINED => Some(false),
+                    _ => None,
+                },
+                Op::And => {
+                    let b = stack.pop().expect("malformed exp");
+                    let a = stack.pop().expect("malformed exp");
+                    and(a, b)
+                }
+                Op::Or => {
+                    let b = stack.pop().expect("malformed exp");
+                    let a = stack.pop().expect("malformed exp");
+                    or(a, b)
+                }
+                Op::Not => stack.pop().expect("malformed exp").map(|a| !a),
+            };
+            stack.push(value);
+        }
+        stack.pop().expect("malformed exp")
+    }
+}
+
+/// A `#if`, `#elif`, `#else` chain
+struct Chain {
+    /// State before the `#if`
+    before: Vars,
+    /// Merged states at the end of each reachable branch
+    after: Option<Vars>,
+    /// If the `#if` itself is reachable
+    reachable: bool,
+    /// Some previous branch was taken, three valued
+    taken: Option<bool>,
+}
+
+struct Analysis<'a> {
+    pre_processor: &'a mut PreProcessor,
+    vars: Vars,
+    include_stack: Vec<SmartString<Compact>>,
+    defines: &'a mut HashSet<SmartString<Compact>>,
+}
+
+impl Analysis<'_> {
+    /// Evaluates a condition and keeps track of the user defines that can change its value
+    fn condition(&mut self, exp: &Exp) -> Option<bool> {
+        let value = self.vars.eval(exp);
+        if value.is_none() {
+            for op in &exp.ops {
+                if let &Op::Var(var) = op {
+                    if self.vars.get(var) & EXTERNAL != 0 {
+                        self.defines.insert(var.into());
+                    }
+                }
+            }
+        }
+        value
+    }
+
+    fn file(&mut self, file_path: &str, file: &File) {
+        let mut chains: Vec<Chain> = vec![];
+        let mut reachable = true;
+
+        for (line_number, line) in file.numbered_lines() {
+            match line {
+                Line::Inc(inc) => {
+                    if !reachable || self.include_stack.iter().any(|path| path == inc) {
+                        // recursive includes are reported by `process`
+                        continue;
+                    }
+
+                    if let Some(inc_file) = self.pre_processor.preload(inc) {
+                        self.include_stack.push((*inc).into());
+                        self.file(inc, inc_file.as_ref());
+                        self.include_stack.pop();
+                    }
+                }
+                &Line::Def(def) if reachable => {
+                    self.vars.0.insert(def.into(), DEFINED);
+                }
+                &Line::Undef(def) if reachable => {
+                    self.vars.0.insert(def.into(), UNDEFINED);
+                }
+                Line::If(exp) => {
+                    let value = if reachable {
+                        self.condition(exp)
+                    } else {
+                        Some(false)
+                    };
+                    chains.push(Chain {
+                        before: self.vars.clone(),
+                        after: None,
+                        reachable,
+                        taken: value,
+                    });
+                    reachable = value != Some(false);
+                }
+                Line::Elif(_) | Line::Else => {
+                    let directive = if let Line::Else = line {
+                        "else"
+                    } else {
+                        "elif"
+                    };
+                    let Some(chain) = chains.last_mut() else {
+                        panic!(
+                            "`{}` doesn't have a maching `if` at {}:{}",
+                            directive, file_path, line_number
+                        );
+                    };
+
+                    // close the previous branch
+                    if reachable {
+                        merge(&mut chain.after, &self.vars);
+                    }
+                    self.vars = chain.before.clone();
+
+                    if !chain.reachable || chain.taken == Some(true) {
+                        reachable = false;
+                        continue;
+                    }
+
+                    let value = match line {
+                        Line::Elif(exp) => self.condition(exp),
+                        _ => Some(true),
+                    };
+                    chain.taken = or(chain.taken, value);
+                    reachable = value != Some(false);
+                }
+                Line::Endif => {
+                    let chain = chains.pop().unwrap_or_else(|| {
+                        panic!(
+                            "`endif` doesn't have a maching `if` at {}:{}",
+                            file_path, line_number
+                        )
+                    });
+
+                    let mut after = chain.after;
+                    if reachable {
+                        merge(&mut after, &self.vars);
+                    }
+
+                    // no branch might be taken at all
+                    if chain.taken != Some(true) {
+                        merge(&mut after, &chain.before);
+                    }
+
+                    self.vars = after.unwrap_or(chain.before);
+                    reachable = chain.reachable;
+                }
+                _ => {}
+            }
+        }
+
+        if !chains.is_empty() {
+            panic!("some `if` block is open in file {}", file_path);
+        }
+    }
+}
+
+/// Three valued `a && b`
+#[inline]
+fn and(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+/// Three valued `a || b`
+#[inline]
+fn or(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+/// Merges `vars` into the states of the branches closed so far
+#[inline]
+fn merge(after: &mut Option<Vars>, vars: &Vars) {
+    match after {
+        Some(after) => after.union(vars),
+        None => *after = Some(vars.clone()),
+    }
+}
+
+impl PreProcessor {
+    /// Finds the defines given by the user that can change the output of `path`, unlike
+    /// [`PreProcessor::find_defines_of`] it leaves out the defines that are only read inside
+    /// blocks that can never be active, or after being `#define`d or `#undef`ined in every path
+    /// that reaches the read, or in conditions that don't depend on them like `1 || A`
+    ///
+    /// The analysis is conservative, a define read by a `#if` which all branches output
+    /// the same text is still reported
+    pub fn find_live_defines_of(
+        &mut self,
+        path: &str,
+        defines: &mut HashSet<SmartString<Compact>>,
+    ) {
+        if let Some(file) = self.preload(path) {
+            let mut analysis = Analysis {
+                pre_processor: self,
+                vars: Vars::new(),
+                include_stack: vec![path.into()],
+                defines,
+            };
+            analysis.file(path, file.as_ref());
+        } else {
+            panic!("file \"{}\" not found", path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryFileLoader;
+
+    use super::*;
+
+    #[test]
+    fn live_defines() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "main.c".into(),
+            "#if 0\n#if DEAD\n#endif\n#endif\n\
+            #define INTERNAL\n#if INTERNAL && LIVE\nlive\n#endif\n\
+            #undef GONE\n#if GONE\ngone\n#endif\n\
+            #if COND\n#define MAYBE\n#endif\n#if MAYBE\nmaybe\n#endif\n\
+            #if COND2\n#define BOTH\n#elif 1\n#define BOTH\n#else\n#if SHADOWED\n#endif\n#endif\n\
+            #if BOTH\nboth\n#endif\n\
+            #if 1 || SHORT\n#endif\n\
+            #include \"inc.h\""
+                .into(),
+        );
+        file_loader
+            .files
+            .insert("inc.h".into(), "#if INC\ninc\n#endif".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let mut defines = HashSet::new();
+        pre_processor.find_live_defines_of("main.c", &mut defines);
+
+        let mut defines: Vec<_> = defines.iter().map(|def| def.as_str()).collect();
+        defines.sort();
+        assert_eq!(defines, ["COND", "COND2", "INC", "LIVE", "MAYBE"]);
+    }
+}
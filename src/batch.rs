@@ -0,0 +1,70 @@
+//! Parallel processing of many define sets, enabled by the `rayon` feature
+
+use alloc::{string::String, vec::Vec};
+
+use rayon::prelude::*;
+
+use crate::{DefineSet, Evaluator, PreProcessor};
+
+impl PreProcessor {
+    /// Processes `root` once for each set of `defines` in parallel, [`PreProcessor::defines`]
+    /// are ignored, outputs are in the same order as `defines`
+    ///
+    /// Every file `root` can include is loaded and parsed once up front, then each thread
+    /// evaluates the cached lines with its own [`Evaluator`] from the borrowed
+    /// [`PreProcessor::cached_files`], see there for the trade-offs of sharing them, an
+    /// include that can't be found only panics when a define set reaches it
+    pub fn process_batch(&mut self, root: &str, defines: &[DefineSet]) -> Vec<String> {
+        self.preload_all(&[root]);
+
+        let files = &self.cached_files();
+        defines
+            .par_iter()
+            .map_init(Evaluator::default, |evaluator, defines| {
+                let mut output = String::new();
+                files.process_blocks(evaluator, root, defines, |text| {
+                    output.push_str(text);
+                    output.push('\n');
+                });
+                output
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smartstring::SmartString;
+
+    use crate::MemoryFileLoader;
+
+    use super::*;
+
+    #[test]
+    fn process_batch() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "main.c".into(),
+            "#if A\n#include \"a.h\"\n#elif B\nb\n#endif\nmain".into(),
+        );
+        file_loader.files.insert("a.h".into(), "a".into());
+
+        let sets: Vec<&[&str]> = vec![&[], &["A"], &["B"], &["A", "B"]];
+        let defines: Vec<DefineSet> = sets
+            .iter()
+            .map(|set| set.iter().map(|&def| SmartString::from(def)).collect())
+            .collect();
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let outputs = pre_processor.process_batch("main.c", &defines);
+        assert_eq!(outputs, ["main\n", "a\nmain\n", "b\nmain\n", "a\nmain\n"]);
+
+        // same outputs as processing one set at the time
+        for (defines, output) in defines.into_iter().zip(&outputs) {
+            pre_processor.defines = defines;
+            let mut expected = String::new();
+            pre_processor.process_to_str("main.c", &mut expected);
+            assert_eq!(&expected, output);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! Command line pre-processor, run `preproc --help` for the usage
+
+use std::{
+    io::Write,
+    panic::{catch_unwind, AssertUnwindSafe},
+    process::ExitCode,
+};
+
+use preproc::{
+    format::Formatter, write_depfile, Config, DefaultFileLoader, DefineSet, File, PreProcessor,
+};
+
+const USAGE: &str = "\
+usage: preproc [options] <input>
+       preproc --list-defines [options] <input>
+       preproc --format [--check] [options] <input>
+       preproc --partial [options] <input>
+
+options:
+    -D NAME[=VALUE]         define NAME, `NAME=0` and `NAME=false` undefine it instead
+    -U NAME                 undefine NAME
+    -I DIR                  search includes in DIR, after the working directory
+    -o FILE                 write the output to FILE instead of the stdout
+    --special-char C        char that starts a directive, default `#`
+    --comment STR           single line comment, default `//`
+    --comment-begin STR     start of a multi-line comment, default `/*`, empty to disable
+    --comment-end STR       end of a multi-line comment, default `*/`
+    --include-begin C       char that starts a include path, default `\"`
+    --include-end C         char that ends a include path, default `\"`
+    -MD                     write a make dependency file to `<out>.d`, needs `-o` for its target
+    -MF FILE                write the dependency file to FILE, implies `-MD`
+    --deps-all              list every file that can be included instead of just the reached ones
+    --list-defines          print the defines the input and its includes use, one per line
+    --partial               only fold the defines given by `-D` and `-U`, the others are kept as `#if`s
+    --format                print the input with nested directives indented, use `-o` to rewrite it
+    --check                 with `--format`, fail if the input isn't formatted instead of printing it
+    -h, --help              print this message";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    input: String,
+    output: Option<String>,
+    /// Each define and if it's defined or undefined, in the order given
+    defines: Vec<(String, bool)>,
+    search_paths: Vec<String>,
+    special_char: Option<u8>,
+    comment: Option<String>,
+    comment_begin: Option<String>,
+    comment_end: Option<String>,
+    include_begin: Option<u8>,
+    include_end: Option<u8>,
+    /// Path of the dependency file
+    depfile: Option<String>,
+    write_depfile: bool,
+    deps_all: bool,
+    list_defines: bool,
+    partial: bool,
+    format: bool,
+    check: bool,
+    help: bool,
+}
+
+fn ascii(option: &str, value: String) -> Result<u8, String> {
+    match value.as_bytes() {
+        &[c] if c.is_ascii() => Ok(c),
+        _ => Err(format!(
+            "`{}` expects a single ASCII char, got \"{}\"",
+            option, value
+        )),
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut input = None;
+
+    while let Some(arg) = args.next() {
+        // values can be given as `-DNAME` or `-D NAME`
+        let (option, inline) = match arg.as_str() {
+            flag @ ("-D" | "-U" | "-I" | "-o") => (flag.to_string(), None),
+            _ => match ["-D", "-U", "-I", "-o"]
+                .iter()
+                .find(|prefix| arg.starts_with(*prefix))
+            {
+                Some(prefix) => (prefix.to_string(), Some(arg[2..].to_string())),
+                None => (arg.clone(), None),
+            },
+        };
+
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for `{}`", option))
+        };
+
+        match option.as_str() {
+            "-D" => {
+                let value = value()?;
+                let define = match value.split_once('=') {
+                    Some((name, "0" | "false")) => (name.to_string(), false),
+                    Some((name, _)) => (name.to_string(), true),
+                    None => (value, true),
+                };
+                options.defines.push(define);
+            }
+            "-U" => options.defines.push((value()?, false)),
+            "-I" => options.search_paths.push(value()?),
+            "-o" => options.output = Some(value()?),
+            "--special-char" => options.special_char = Some(ascii(&option, value()?)?),
+            "--comment" => options.comment = Some(value()?),
+            "--comment-begin" => options.comment_begin = Some(value()?),
+            "--comment-end" => options.comment_end = Some(value()?),
+            "--include-begin" => options.include_begin = Some(ascii(&option, value()?)?),
+            "--include-end" => options.include_end = Some(ascii(&option, value()?)?),
+            "-MD" => options.write_depfile = true,
+            "-MF" => {
+                options.write_depfile = true;
+                options.depfile = Some(value()?);
+            }
+            "--deps-all" => options.deps_all = true,
+            "--list-defines" => options.list_defines = true,
+            "--partial" => options.partial = true,
+            "--format" => options.format = true,
+            "--check" => options.check = true,
+            "-h" | "--help" => options.help = true,
+            _ if option.starts_with('-') && option.len() > 1 => {
+                return Err(format!("unknown option `{}`", option))
+            }
+            _ => {
+                if input.replace(arg).is_some() {
+                    return Err("only one input file is supported".into());
+                }
+            }
+        }
+    }
+
+    match input {
+        Some(input) => options.input = input,
+        None if options.help => {}
+        None => return Err("missing input file".into()),
+    }
+
+    // the input as the target would make a rule that depends on itself
+    if options.write_depfile && options.output.is_none() && !options.help {
+        return Err("`-MD` and `-MF` need `-o` as the target of the dependency file".into());
+    }
+
+    Ok(options)
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut config = Config::default();
+    if let Some(special_char) = options.special_char {
+        config.special_char = special_char;
+    }
+    if let Some(comment) = options.comment {
+        config.comment = comment.as_str().into();
+    }
+    if let Some(comment_begin) = options.comment_begin {
+        config.comment_begin = comment_begin.as_str().into();
+    }
+    if let Some(comment_end) = options.comment_end {
+        config.comment_end = comment_end.as_str().into();
+    }
+    if let Some(include_begin) = options.include_begin {
+        config.include_begin = include_begin;
+    }
+    if let Some(include_end) = options.include_end {
+        config.include_end = include_end;
+    }
+
+    if options.format {
+        let data = std::fs::read_to_string(&options.input)
+            .map_err(|err| format!("couldn't read \"{}\", {}", options.input, err))?;
+        let file = File::parse_with_path(data.clone(), &config, &options.input);
+        let output = Formatter::default().format(&file, &config);
+
+        if options.check {
+            if output != data {
+                return Err(format!("\"{}\" isn't formatted", options.input));
+            }
+            return Ok(());
+        }
+        return match &options.output {
+            Some(path) => write(path, output.as_bytes()),
+            None => std::io::stdout()
+                .write_all(output.as_bytes())
+                .map_err(|err| err.to_string()),
+        };
+    }
+
+    let mut pre_processor = PreProcessor::with_loader(DefaultFileLoader {
+        search_paths: options.search_paths,
+    });
+    pre_processor.config = config;
+    let mut undefined = DefineSet::default();
+    for (name, defined) in options.defines {
+        if defined {
+            pre_processor.defines.insert(name.as_str().into());
+            undefined.remove(name.as_str());
+        } else {
+            pre_processor.defines.remove(name.as_str());
+            undefined.insert(name.as_str().into());
+        }
+    }
+
+    let mut output = String::new();
+    if options.list_defines {
+        let mut defines = Default::default();
+        pre_processor.find_defines_of(&options.input, &mut defines);
+
+        let mut defines: Vec<_> = defines.iter().map(|def| def.as_str()).collect();
+        defines.sort();
+        for def in defines {
+            output.push_str(def);
+            output.push('\n');
+        }
+    } else if options.partial {
+        let defined = pre_processor.defines.clone();
+        pre_processor.partial_process_to_str(&options.input, &defined, &undefined, &mut output);
+    } else {
+        pre_processor.process_to_str(&options.input, &mut output);
+    }
+
+    if options.write_depfile {
+        let target = options.output.as_ref().expect("`-MD` without `-o`");
+        let path = options.depfile.unwrap_or_else(|| format!("{}.d", target));
+        // partial processing can reach any file and listing the defines doesn't evaluate it
+        let dependencies = if options.deps_all || options.partial || options.list_defines {
+            pre_processor.all_dependencies(&options.input)
+        } else {
+            pre_processor.dependencies()
+        };
+
+        let mut depfile = vec![];
+        write_depfile(&mut depfile, target, &dependencies).map_err(|err| err.to_string())?;
+        write(&path, &depfile)?;
+    }
+
+    match &options.output {
+        Some(path) => write(path, output.as_bytes()),
+        None => std::io::stdout()
+            .write_all(output.as_bytes())
+            .map_err(|err| err.to_string()),
+    }
+}
+
+fn write(path: &str, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|err| format!("couldn't write to \"{}\", {}", path, err))
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) if options.help => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    // errors are reported by panics with the file and line, print just the message
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = catch_unwind(AssertUnwindSafe(|| run(options))).unwrap_or_else(|err| {
+        Err(err
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| err.downcast_ref::<&str>().map(|err| err.to_string()))
+            .unwrap_or_else(|| "unknown error".into()))
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Result<Options, String> {
+        parse_args(args.split(' ').map(String::from))
+    }
+
+    #[test]
+    fn parse() {
+        let options =
+            args("-D A -DB=1 -D C=0 -UD -I inc -Ilib -o out.wgsl --special-char @ main.wgsl")
+                .unwrap();
+        assert_eq!(
+            options,
+            Options {
+                input: "main.wgsl".into(),
+                output: Some("out.wgsl".into()),
+                defines: vec![
+                    ("A".into(), true),
+                    ("B".into(), true),
+                    ("C".into(), false),
+                    ("D".into(), false),
+                ],
+                search_paths: vec!["inc".into(), "lib".into()],
+                special_char: Some(b'@'),
+                ..Default::default()
+            }
+        );
+
+        assert!(args("--list-defines main.c").unwrap().list_defines);
+
+        assert!(args("--partial -D A -U B main.c").unwrap().partial);
+
+        let options = args("--format --check main.c").unwrap();
+        assert!(options.format && options.check);
+
+        let options = args("main.c -o out.c -MF deps.d --deps-all").unwrap();
+        assert!(options.write_depfile && options.deps_all);
+        assert_eq!(options.depfile.as_deref(), Some("deps.d"));
+        assert!(args("main.c -D").is_err());
+        assert!(args("-MD main.c").is_err());
+        assert!(args("-MF deps.d main.c").is_err());
+        assert!(args("main.c --special-char ##").is_err());
+        assert!(args("main.c other.c").is_err());
+        assert!(args("--unknown main.c").is_err());
+        assert!(args("-é main.c").is_err());
+        assert_eq!(args("-Dé main.c").unwrap().defines, [("é".into(), true)]);
+        assert!(args("-D A").is_err());
+    }
+
+    /// Runs `options` over `files` written to a temporary directory, returns the output
+    /// and the dependency file if any
+    fn run_with(
+        files: &[(&str, &str)],
+        mut options: Options,
+    ) -> Result<(String, Option<String>), String> {
+        let dir =
+            std::env::temp_dir().join(format!("preproc-cli-{}-{}", std::process::id(), files[0].0));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (path, data) in files {
+            std::fs::write(dir.join(path), data).unwrap();
+        }
+
+        let output = dir.join("out").to_str().unwrap().to_string();
+        options.input = dir.join(files[0].0).to_str().unwrap().into();
+        options.search_paths.push(dir.to_str().unwrap().into());
+        options.output = Some(output.clone());
+        let result = catch_unwind(AssertUnwindSafe(|| run(options)))
+            .unwrap_or_else(|err| Err(err.downcast_ref::<String>().cloned().unwrap_or_default()))
+            .map(|()| {
+                let depfile = std::fs::read_to_string(format!("{}.d", output)).ok();
+                (std::fs::read_to_string(output).unwrap(), depfile)
+            });
+
+        std::fs::remove_dir_all(dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn list_defines_of_recursive_include() {
+        let options = Options {
+            list_defines: true,
+            ..Default::default()
+        };
+        let output = run_with(&[("t4.c", "#include \"t4.c\"\n#if A\n#endif")], options);
+        assert_eq!(output.unwrap(), ("A\n".into(), None));
+    }
+
+    #[test]
+    fn list_defines_depfile() {
+        let options = Options {
+            list_defines: true,
+            write_depfile: true,
+            ..Default::default()
+        };
+        let output = run_with(
+            &[
+                ("t7.c", "#include \"inc.c\"\n#if A\n#endif"),
+                ("inc.c", "#if B\n#endif"),
+            ],
+            options,
+        );
+        let (output, depfile) = output.unwrap();
+        let depfile = depfile.unwrap();
+        assert_eq!(output, "A\nB\n");
+        assert!(depfile.contains("t7.c \\\n"), "{}", depfile);
+        assert!(depfile.ends_with("inc.c\n"), "{}", depfile);
+    }
+
+    #[test]
+    fn parse_error_path() {
+        let err = run_with(&[("t5.c", "#if (")], Options::default()).unwrap_err();
+        assert!(err.starts_with("unmached `(` at "), "{}", err);
+        assert!(err.ends_with("t5.c:1:5"), "{}", err);
+
+        let options = Options {
+            format: true,
+            ..Default::default()
+        };
+        let err = run_with(&[("t6.c", "#if (")], options).unwrap_err();
+        assert!(err.ends_with("t6.c:1:5"), "{}", err);
+    }
+}
@@ -0,0 +1,284 @@
+//! Pre-processes files in `build.rs`, enabled by the `build` feature
+//!
+//! ```no_run
+//! // build.rs
+//! preproc::build::Build::new()
+//!     .file("shaders/pbr.wgsl", &[&[], &["SKINNED"], &["SKINNED", "VERTEX_UVS"]])
+//!     .run();
+//! ```
+//!
+//! Then include the generated module somewhere in the crate
+//!
+//! ```ignore
+//! mod shaders {
+//!     include!(concat!(env!("OUT_DIR"), "/preproc.rs"));
+//! }
+//!
+//! let source: &str = shaders::SHADERS_PBR_WGSL__SKINNED;
+//! ```
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+use std::path::PathBuf;
+
+use hashbrown::HashMap;
+
+use crate::{DefaultFileLoader, DefineSet, PreProcessor};
+
+pub struct Build {
+    /// Loads the files relative to the crate root by default
+    pub pre_processor: PreProcessor,
+    /// Where the outputs and the module are written, default `OUT_DIR`
+    pub out_dir: PathBuf,
+    /// File name of the generated module, default "preproc.rs"
+    pub module: String,
+    /// Root files and the define sets they are processed with, see [`Build::file`]
+    pub files: Vec<(String, Vec<DefineSet>)>,
+}
+
+impl Default for Build {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Build {
+    pub fn new() -> Self {
+        Self {
+            // build scripts run from the crate root
+            pre_processor: PreProcessor::with_loader(DefaultFileLoader {
+                search_paths: vec![],
+            }),
+            out_dir: std::env::var_os("OUT_DIR").unwrap_or_default().into(),
+            module: "preproc.rs".into(),
+            files: vec![],
+        }
+    }
+
+    /// Processes `path` once for each set of `defines`
+    pub fn file(&mut self, path: &str, defines: &[&[&str]]) -> &mut Self {
+        let defines = defines
+            .iter()
+            .map(|defines| defines.iter().map(|&def| def.into()).collect())
+            .collect();
+        self.files.push((path.into(), defines));
+        self
+    }
+
+    /// Writes every output and the module with a `const` for each of them, named after
+    /// the file path followed by its defines sorted and separated by `__`, like
+    /// `SHADERS_PBR_WGSL__SKINNED`, and a `_PERMUTATIONS` slice of the defines and
+    /// outputs of each file
+    ///
+    /// Emits `cargo:rerun-if-changed` for every file that can be included, returns the path
+    /// of the module, panics on errors like build scripts usually do, also when two consts
+    /// end up with the same name
+    pub fn run(&mut self) -> PathBuf {
+        assert!(
+            !self.out_dir.as_os_str().is_empty(),
+            "`OUT_DIR` isn't set, is this running from a build script?"
+        );
+
+        // every const generated and the file it comes from, different paths can end up
+        // with the same name once turned into identifiers
+        let mut names = HashMap::new();
+        for (path, permutations) in &self.files {
+            let name = const_name(path);
+            unique_name(&mut names, format!("{}_PERMUTATIONS", name), path);
+            for defines in permutations {
+                unique_name(&mut names, permutation_name(&name, &sorted(defines)), path);
+            }
+        }
+
+        let outputs = self.out_dir.join("preproc");
+        std::fs::create_dir_all(&outputs)
+            .unwrap_or_else(|err| panic!("couldn't create {:?}, {}", outputs, err));
+
+        let mut module = String::from("// generated by preproc, don't edit\n");
+
+        for (path, permutations) in &self.files {
+            for dependency in rerun_if_changed(&mut self.pre_processor, path) {
+                println!("cargo:rerun-if-changed={}", dependency);
+            }
+
+            let name = const_name(path);
+            let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+
+            let mut list = format!(
+                "/// Every permutation of \"{}\"\npub const {}_PERMUTATIONS: &[(&[&str], &str)] = &[\n",
+                path, name
+            );
+
+            for defines in permutations {
+                let sorted = sorted(defines);
+                let const_name = permutation_name(&name, &sorted);
+
+                self.pre_processor.defines = defines.clone();
+                let mut output = String::new();
+                self.pre_processor.process_to_str(path, &mut output);
+
+                let output_path = outputs.join(format!("{}.{}", const_name, extension));
+                std::fs::write(&output_path, output)
+                    .unwrap_or_else(|err| panic!("couldn't write {:?}, {}", output_path, err));
+
+                let output_path = output_path
+                    .to_str()
+                    .unwrap_or_else(|| panic!("{:?} isn't valid UTF-8", output_path));
+                // the debug format of a `str` is an escaped string literal
+                writeln!(
+                    module,
+                    "pub const {}: &str = include_str!({:?});",
+                    const_name, output_path
+                )
+                .unwrap();
+                writeln!(list, "    (&{:?}, {}),", sorted, const_name).unwrap();
+            }
+
+            list.push_str("];\n");
+            module.push_str(&list);
+        }
+
+        let module_path = self.out_dir.join(&self.module);
+        std::fs::write(&module_path, module)
+            .unwrap_or_else(|err| panic!("couldn't write {:?}, {}", module_path, err));
+        module_path
+    }
+}
+
+/// Every file `root` can include, plus the includes that couldn't be found so adding
+/// them later reruns the build script
+fn rerun_if_changed(pre_processor: &mut PreProcessor, root: &str) -> Vec<String> {
+    let mut paths = pre_processor.all_dependencies(root);
+    let graph = pre_processor.include_graph(&[root]);
+    for path in graph.dependencies_of(root) {
+        if !pre_processor.files.contains_key(path) {
+            paths.push(path.into());
+        }
+    }
+    paths
+}
+
+/// Names of `defines` in order
+fn sorted(defines: &DefineSet) -> Vec<&str> {
+    let mut sorted: Vec<&str> = defines.iter().map(|def| def.as_str()).collect();
+    sorted.sort();
+    sorted
+}
+
+/// `name` of the file followed by each define, separated by `__`
+fn permutation_name(name: &str, sorted: &[&str]) -> String {
+    let mut permutation_name = String::from(name);
+    for def in sorted {
+        permutation_name.push_str("__");
+        permutation_name.push_str(&const_name(def));
+    }
+    permutation_name
+}
+
+/// Adds the const `name` generated from `path`, panics if another file already uses it
+fn unique_name<'a>(names: &mut HashMap<String, &'a str>, name: String, path: &'a str) {
+    if let Some(other) = names.insert(name.clone(), path) {
+        panic!(
+            "`{}` is generated twice, from \"{}\" and \"{}\"",
+            name, other, path
+        );
+    }
+}
+
+/// Upper case identifier, anything that isn't alphanumeric becomes a `_`
+fn const_name(path: &str) -> String {
+    let mut name: String = path
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryFileLoader;
+
+    use super::*;
+
+    #[test]
+    fn build() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "shaders/pbr.wgsl".into(),
+            "#if SKINNED\n#include \"skin.wgsl\"\n#endif\npbr".into(),
+        );
+        file_loader.files.insert("skin.wgsl".into(), "skin".into());
+
+        let out_dir = std::env::temp_dir().join(format!("preproc-build-{}", std::process::id()));
+        let mut build = Build {
+            pre_processor: PreProcessor::with_loader(file_loader),
+            out_dir: out_dir.clone(),
+            ..Default::default()
+        };
+        let module = build.file("shaders/pbr.wgsl", &[&[], &["SKINNED"]]).run();
+
+        let module = std::fs::read_to_string(module).unwrap();
+        let output = out_dir
+            .join("preproc")
+            .join("SHADERS_PBR_WGSL__SKINNED.wgsl");
+        assert!(module.contains(&format!(
+            "pub const SHADERS_PBR_WGSL__SKINNED: &str = include_str!({:?});",
+            output.to_str().unwrap()
+        )));
+        assert!(module.contains("(&[\"SKINNED\"], SHADERS_PBR_WGSL__SKINNED),"));
+        assert!(module.contains("(&[], SHADERS_PBR_WGSL),"));
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "skin\npbr\n");
+
+        std::fs::remove_dir_all(out_dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "`A_WGSL_PERMUTATIONS` is generated twice, from \"a.wgsl\" and \"a-wgsl\""
+    )]
+    fn same_name() {
+        // nothing is written when the names collide
+        let mut build = Build {
+            out_dir: "unused".into(),
+            ..Default::default()
+        };
+        build.file("a.wgsl", &[&[]]).file("a-wgsl", &[]).run();
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "`A_PERMUTATIONS` is generated twice, from \"a\" and \"a.permutations\""
+    )]
+    fn same_name_as_permutations() {
+        let mut build = Build {
+            out_dir: "unused".into(),
+            ..Default::default()
+        };
+        build.file("a", &[]).file("a.permutations", &[&[]]).run();
+    }
+
+    #[test]
+    fn missing_includes() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "main.wgsl".into(),
+            "#include \"common.wgsl\"\n#if GENERATED\n#include \"generated.wgsl\"\n#endif".into(),
+        );
+        file_loader.files.insert("common.wgsl".into(), "".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        assert_eq!(
+            rerun_if_changed(&mut pre_processor, "main.wgsl"),
+            ["main.wgsl", "common.wgsl", "generated.wgsl"]
+        );
+    }
+}
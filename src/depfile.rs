@@ -0,0 +1,113 @@
+//! Make compatible dependency files, see [`write_depfile`]
+
+use alloc::{string::String, vec::Vec};
+use std::io::{self, Write};
+
+use crate::PreProcessor;
+
+impl PreProcessor {
+    /// Files reached by the last evaluator run in the order they were first reached, starting
+    /// with the processed file, includes inside inactive blocks aren't listed, paths are
+    /// resolved with [`crate::FileLoader::resolve`]
+    ///
+    /// Every method that evaluates a file starts a new run, not only [`PreProcessor::process`]
+    /// but also [`PreProcessor::permutations`], [`PreProcessor::cache_key`] and
+    /// [`PreProcessor::inactive_ranges`]
+    pub fn dependencies(&self) -> Vec<String> {
+        self.evaluator
+            .reached
+            .iter()
+            .map(|path| self.resolve(path))
+            .collect()
+    }
+
+    /// Every file `root` can include in any branch sorted by path, starting with `root`,
+    /// includes that can't be found are left out
+    pub fn all_dependencies(&mut self, root: &str) -> Vec<String> {
+        let graph = self.include_graph(&[root]);
+        let mut dependencies = vec![self.resolve(root)];
+        for path in graph.dependencies_of(root) {
+            if self.files.contains_key(path) {
+                dependencies.push(self.resolve(path));
+            }
+        }
+        dependencies
+    }
+
+    fn resolve(&self, path: &str) -> String {
+        self.file_loader
+            .resolve(path)
+            .unwrap_or_else(|| String::from(path))
+    }
+}
+
+/// Writes a make rule `target: dependencies...` that ninja also understands,
+/// chars make treats as special are escaped
+pub fn write_depfile(
+    mut writer: impl Write,
+    target: &str,
+    dependencies: &[String],
+) -> io::Result<()> {
+    write_path(&mut writer, target)?;
+    write!(writer, ":")?;
+    for dependency in dependencies {
+        write!(writer, " \\\n ")?;
+        write_path(&mut writer, dependency)?;
+    }
+    writeln!(writer)
+}
+
+fn write_path(writer: &mut impl Write, path: &str) -> io::Result<()> {
+    for c in path.chars() {
+        match c {
+            ' ' | '#' => write!(writer, "\\{}", c)?,
+            '$' => write!(writer, "$$")?,
+            _ => write!(writer, "{}", c)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryFileLoader;
+
+    use super::*;
+
+    #[test]
+    fn depfile() {
+        let mut file_loader = MemoryFileLoader::default();
+        for (path, data) in [
+            (
+                "main.wgsl",
+                "#include \"a.wgsl\"\n#if B\n#include \"my b.wgsl\"\n#endif\n#include \"a.wgsl\"",
+            ),
+            ("a.wgsl", "#if 0\n#include \"missing.wgsl\"\n#endif"),
+            ("my b.wgsl", "b"),
+        ] {
+            file_loader.files.insert(path.into(), data.into());
+        }
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let mut output = String::new();
+        pre_processor.process_to_str("main.wgsl", &mut output);
+        assert_eq!(pre_processor.dependencies(), ["main.wgsl", "a.wgsl"]);
+
+        let mut depfile = vec![];
+        write_depfile(&mut depfile, "out/main.wgsl", &pre_processor.dependencies()).unwrap();
+        assert_eq!(
+            String::from_utf8(depfile).unwrap(),
+            "out/main.wgsl: \\\n main.wgsl \\\n a.wgsl\n"
+        );
+
+        let all = pre_processor.all_dependencies("main.wgsl");
+        assert_eq!(all, ["main.wgsl", "a.wgsl", "my b.wgsl"]);
+
+        let mut depfile = vec![];
+        write_depfile(&mut depfile, "out$", &all).unwrap();
+        assert_eq!(
+            String::from_utf8(depfile).unwrap(),
+            "out$$: \\\n main.wgsl \\\n a.wgsl \\\n my\\ b.wgsl\n"
+        );
+    }
+}
@@ -60,6 +60,7 @@ pub struct Exp<'a> {
 
 impl<'a> Exp<'a> {
     #[inline]
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(exp: &'a str) -> Result<Self, Error> {
         crate::parse_exp(exp)
     }
@@ -118,6 +119,340 @@ impl<'a> Exp<'a> {
 
         ctx.stack.pop().unwrap()
     }
+
+    /// Evaluates the known variables and folds the operators they decide, `known` gives
+    /// `None` for the unknown ones, returns the value when `known` decides the expression,
+    /// otherwise what is left of it like `X` for `A && X` with `A` true
+    pub fn partial_eval(&self, known: impl Fn(&str) -> Option<bool>) -> Result<bool, Exp<'a>> {
+        // each operand is either a value or the ops of what is left of it
+        let mut stack: SmallVec<[Result<bool, Vec<Op<'a>>>; 16]> = SmallVec::new();
+        for op in &self.ops {
+            let value = match *op {
+                Op::Var(var) => known(var).ok_or_else(|| vec![Op::Var(var)]),
+                Op::And | Op::Or => {
+                    let b = stack.pop().expect("malformed exp");
+                    let a = stack.pop().expect("malformed exp");
+                    // the value that decides the operator alone
+                    let decisive = matches!(op, Op::Or);
+                    match (a, b) {
+                        (Ok(a), _) | (_, Ok(a)) if a == decisive => Ok(decisive),
+                        (Ok(_), b) | (b, Ok(_)) => b,
+                        (Err(mut a), Err(b)) => {
+                            a.extend(b);
+                            a.push(*op);
+                            Err(a)
+                        }
+                    }
+                }
+                Op::Not => match stack.pop().expect("malformed exp") {
+                    Ok(a) => Ok(!a),
+                    Err(mut a) => {
+                        a.push(Op::Not);
+                        Err(a)
+                    }
+                },
+            };
+            stack.push(value);
+        }
+
+        match stack.pop().expect("malformed exp") {
+            Ok(value) => Ok(value),
+            Err(ops) => Err(Exp { ops }),
+        }
+    }
+
+    /// Displays the expression without redundant parentheses, `!` binds tighter than
+    /// the other operators, `&&` and `||` share the same precedence when parsed,
+    /// so a mix of them is always parenthesized to read the same as in C
+    pub fn minimal(&self) -> Minimal<'_, 'a> {
+        Minimal(self)
+    }
+
+    /// Equivalent expression with the constants `true`, `1`, `false` and `0` folded,
+    /// negations pushed down to the variables by De Morgan's laws, double negations removed,
+    /// nested operators flattened and duplicated, complementary or absorbed operands
+    /// like `A` in `B || A && B` removed
+    pub fn simplify(&self) -> Exp<'a> {
+        Node::from_ops(&self.ops).nnf(false).normalize().to_exp()
+    }
+
+    /// Equivalent expression in disjunctive normal form, a `||` of `&&`s of variables
+    /// or negated variables
+    ///
+    /// Keep in mind the number of terms can grow exponentially with the number of operators
+    pub fn to_dnf(&self) -> Exp<'a> {
+        let node = Node::from_ops(&self.ops).nnf(false).normalize();
+        let terms = node.terms(true).into_iter().map(Node::And).collect();
+        Node::Or(terms).normalize().to_exp()
+    }
+
+    /// Equivalent expression in conjunctive normal form, a `&&` of `||`s of variables
+    /// or negated variables
+    ///
+    /// Keep in mind the number of terms can grow exponentially with the number of operators
+    pub fn to_cnf(&self) -> Exp<'a> {
+        let node = Node::from_ops(&self.ops).nnf(false).normalize();
+        let terms = node.terms(false).into_iter().map(Node::Or).collect();
+        Node::And(terms).normalize().to_exp()
+    }
+
+    /// Both expressions have the same value for every assignment of the union
+    /// of their variables, `None` when they have more than [`MAX_VARS`] variables
+    /// since the number of assignments doubles with each one
+    pub fn equivalent(&self, other: &Exp) -> Option<bool> {
+        let vars = vars([self, other]);
+        if vars.len() > MAX_VARS {
+            return None;
+        }
+        Some(self.truth_table(&vars) == other.truth_table(&vars))
+    }
+
+    /// Value for every assignment of `vars`, the bit `i` of each row is the value of `vars[i]`
+    pub(crate) fn truth_table(&self, vars: &[&str]) -> Vec<bool> {
+        let mut ctx = Ctx::default();
+        (0..1usize << vars.len())
+            .map(|row| {
+                ctx.clear();
+                for (i, var) in vars.iter().enumerate() {
+                    if row & (1 << i) != 0 {
+                        ctx.vars.insert((*var).into());
+                    }
+                }
+                self.eval(&mut ctx)
+            })
+            .collect()
+    }
+}
+
+/// Expressions with more variables than this aren't compared with truth tables
+pub const MAX_VARS: usize = 16;
+
+/// Variables of `exps` without the constants, in order of appearance
+pub(crate) fn vars<'a, 'b: 'a>(exps: impl IntoIterator<Item = &'a Exp<'b>>) -> Vec<&'b str> {
+    let mut vars: Vec<&str> = vec![];
+    for op in exps.into_iter().flat_map(|exp| &exp.ops) {
+        if let &Op::Var(var) = op {
+            if !matches!(var, "true" | "1" | "false" | "0") && !vars.contains(&var) {
+                vars.push(var);
+            }
+        }
+    }
+    vars
+}
+
+/// Tree form of an expression used to simplify it
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node<'a> {
+    Const(bool),
+    Var(&'a str),
+    Not(Box<Node<'a>>),
+    And(Vec<Node<'a>>),
+    Or(Vec<Node<'a>>),
+}
+
+impl<'a> Node<'a> {
+    fn from_ops(ops: &[Op<'a>]) -> Self {
+        let mut stack = Vec::with_capacity(8);
+        for op in ops {
+            let node = match *op {
+                Op::Var("true" | "1") => Node::Const(true),
+                Op::Var("false" | "0") => Node::Const(false),
+                Op::Var(var) => Node::Var(var),
+                Op::And | Op::Or => {
+                    let b = stack.pop().expect("malformed exp");
+                    let a = stack.pop().expect("malformed exp");
+                    if let Op::And = op {
+                        Node::And(vec![a, b])
+                    } else {
+                        Node::Or(vec![a, b])
+                    }
+                }
+                Op::Not => Node::Not(Box::new(stack.pop().expect("malformed exp"))),
+            };
+            stack.push(node);
+        }
+
+        if stack.len() != 1 {
+            panic!("malformed exp");
+        }
+        stack.pop().unwrap()
+    }
+
+    fn to_exp(&self) -> Exp<'a> {
+        let mut exp = Exp::default();
+        self.to_ops(&mut exp.ops);
+        exp
+    }
+
+    fn to_ops(&self, ops: &mut Vec<Op<'a>>) {
+        match self {
+            Node::Const(value) => ops.push(Op::Var(if *value { "true" } else { "false" })),
+            Node::Var(var) => ops.push(Op::Var(var)),
+            Node::Not(node) => {
+                node.to_ops(ops);
+                ops.push(Op::Not);
+            }
+            Node::And(nodes) | Node::Or(nodes) => {
+                let (op, empty) = match self {
+                    Node::And(_) => (Op::And, true),
+                    _ => (Op::Or, false),
+                };
+                match nodes.split_first() {
+                    Some((first, rest)) => {
+                        first.to_ops(ops);
+                        for node in rest {
+                            node.to_ops(ops);
+                            ops.push(op);
+                        }
+                    }
+                    None => Node::Const(empty).to_ops(ops),
+                }
+            }
+        }
+    }
+
+    /// Negation normal form, only variables are negated
+    fn nnf(self, negate: bool) -> Self {
+        match self {
+            Node::Const(value) => Node::Const(value != negate),
+            Node::Var(_) if negate => Node::Not(Box::new(self)),
+            Node::Var(_) => self,
+            Node::Not(node) => node.nnf(!negate),
+            Node::And(nodes) => {
+                let nodes = nodes.into_iter().map(|node| node.nnf(negate)).collect();
+                if negate {
+                    Node::Or(nodes)
+                } else {
+                    Node::And(nodes)
+                }
+            }
+            Node::Or(nodes) => {
+                let nodes = nodes.into_iter().map(|node| node.nnf(negate)).collect();
+                if negate {
+                    Node::And(nodes)
+                } else {
+                    Node::Or(nodes)
+                }
+            }
+        }
+    }
+
+    /// Simplifies a tree in negation normal form until nothing changes
+    fn normalize(self) -> Self {
+        let mut node = self;
+        loop {
+            let next = node.clone().normalize_once();
+            if next == node {
+                return node;
+            }
+            node = next;
+        }
+    }
+
+    fn normalize_once(self) -> Self {
+        let (nodes, is_and) = match self {
+            Node::And(nodes) => (nodes, true),
+            Node::Or(nodes) => (nodes, false),
+            _ => return self,
+        };
+        // value that decides the operator alone and the one ignored by it
+        let (decisive, identity) = (!is_and, is_and);
+
+        let mut operands: Vec<Node<'a>> = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            match node.normalize_once() {
+                Node::Const(value) if value == decisive => return Node::Const(decisive),
+                Node::Const(_) => {}
+                // `a && (b && c)` is `a && b && c`
+                Node::And(nested) if is_and => operands.extend(nested),
+                Node::Or(nested) if !is_and => operands.extend(nested),
+                node => operands.push(node),
+            }
+        }
+
+        // duplicates, `a && a` is `a`
+        let mut i = 0;
+        while i < operands.len() {
+            if operands[..i].contains(&operands[i]) {
+                operands.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        // complements, `a && !a` is `false`
+        for operand in &operands {
+            let complement = match operand {
+                Node::Not(node) => (**node).clone(),
+                node => Node::Not(Box::new(node.clone())),
+            };
+            if operands.contains(&complement) {
+                return Node::Const(decisive);
+            }
+        }
+
+        // absorption, `a && (a || b)` is `a`
+        let dual = |node: &Node<'a>| -> Vec<Node<'a>> {
+            match (node, is_and) {
+                (Node::Or(nodes), true) | (Node::And(nodes), false) => nodes.clone(),
+                _ => vec![node.clone()],
+            }
+        };
+        let duals: Vec<Vec<Node<'a>>> = operands.iter().map(dual).collect();
+        let mut absorbed = vec![false; operands.len()];
+        for i in 0..operands.len() {
+            absorbed[i] = (0..operands.len()).any(|j| {
+                // of two equal sets only the last one is absorbed
+                j != i
+                    && !absorbed[j]
+                    && duals[j].iter().all(|node| duals[i].contains(node))
+                    && (duals[j].len() < duals[i].len() || j < i)
+            });
+        }
+        let mut absorbed = absorbed.into_iter();
+        operands.retain(|_| !absorbed.next().unwrap());
+
+        match operands.len() {
+            0 => Node::Const(identity),
+            1 => operands.pop().unwrap(),
+            _ if is_and => Node::And(operands),
+            _ => Node::Or(operands),
+        }
+    }
+
+    /// Terms of the disjunctive normal form when `dnf`, otherwise the clauses of
+    /// the conjunctive normal form, of a tree in negation normal form
+    fn terms(&self, dnf: bool) -> Vec<Vec<Node<'a>>> {
+        match self {
+            // no terms is `false` in DNF and `true` in CNF, a empty term is the opposite
+            Node::Const(value) if *value == dnf => vec![vec![]],
+            Node::Const(_) => vec![],
+            Node::Var(_) | Node::Not(_) => vec![vec![self.clone()]],
+            Node::And(nodes) | Node::Or(nodes) => {
+                if matches!(self, Node::Or(_)) == dnf {
+                    // the terms of each operand together
+                    nodes.iter().flat_map(|node| node.terms(dnf)).collect()
+                } else {
+                    // every combination of a term of each operand
+                    let mut terms = vec![vec![]];
+                    for node in nodes {
+                        let node_terms = node.terms(dnf);
+                        terms = terms
+                            .iter()
+                            .flat_map(|term| {
+                                node_terms.iter().map(move |other| {
+                                    let mut term: Vec<Node<'a>> = term.clone();
+                                    term.extend(other.iter().cloned());
+                                    term
+                                })
+                            })
+                            .collect();
+                    }
+                    terms
+                }
+            }
+        }
+    }
 }
 
 impl<'a> fmt::Display for Exp<'a> {
@@ -153,6 +488,45 @@ impl<'a> fmt::Display for Exp<'a> {
     }
 }
 
+/// See [`Exp::minimal`]
+pub struct Minimal<'b, 'a>(&'b Exp<'a>);
+
+impl<'b, 'a> fmt::Display for Minimal<'b, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // each operand and its outermost operator, `None` for variables and `!`
+        let mut stack: SmallVec<[(Cow<'a, str>, Option<Op<'a>>); 16]> = SmallVec::new();
+        let operand = |stack: &mut SmallVec<_>, parent: Option<Op<'a>>| {
+            let (exp, op): (Cow<'a, str>, Option<Op<'a>>) = stack.pop().ok_or(fmt::Error)?;
+            Ok(if op.is_some() && op != parent {
+                Cow::owned(format!("({})", exp))
+            } else {
+                exp
+            })
+        };
+
+        for op in &self.0.ops {
+            match op {
+                Op::Var(var) => stack.push((Cow::borrowed(var), None)),
+                Op::And | Op::Or => {
+                    let b = operand(&mut stack, Some(*op))?;
+                    let a = operand(&mut stack, Some(*op))?;
+                    let op_str = if let Op::And = op { "&&" } else { "||" };
+                    stack.push((Cow::owned(format!("{} {} {}", a, op_str, b)), Some(*op)));
+                }
+                Op::Not => {
+                    let a = operand(&mut stack, None)?;
+                    stack.push((Cow::owned(format!("!{}", a)), None));
+                }
+            }
+        }
+
+        match stack.as_slice() {
+            [(exp, _)] => write!(f, "{}", exp),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fmt::Write;
@@ -200,6 +574,147 @@ mod tests {
         );
     }
 
+    #[test]
+    fn minimal() {
+        fn minimal(exp: &str) -> String {
+            let exp = Exp::from_str(exp).expect("failed to parse expression");
+            let mut text = String::default();
+            write!(text, "{}", exp.minimal()).expect("malformed expression");
+            text
+        }
+
+        assert_eq!(minimal("a"), "a");
+        assert_eq!(minimal("((a))"), "a");
+        assert_eq!(minimal("!(a)"), "!a");
+        assert_eq!(minimal("!!a"), "!!a");
+        assert_eq!(minimal("(a && b) || c"), "(a && b) || c");
+        assert_eq!(minimal("a && (b || c)"), "a && (b || c)");
+        assert_eq!(minimal("a || b && c"), "(a || b) && c");
+        assert_eq!(minimal("(a || b) && !(c || d)"), "(a || b) && !(c || d)");
+        assert_eq!(minimal("a || (b || c)"), "a || b || c");
+        assert_eq!(minimal("a && b && (c && d)"), "a && b && c && d");
+        assert_eq!(minimal("!(a && b)"), "!(a && b)");
+        assert_eq!(minimal("!b&&a"), "!b && a");
+    }
+
+    #[test]
+    fn partial_eval() {
+        fn partial_eval(exp: &str) -> Result<bool, std::string::String> {
+            let exp = Exp::from_str(exp).expect("failed to parse expression");
+            exp.partial_eval(|var| match var {
+                "A" | "1" => Some(true),
+                "B" | "0" => Some(false),
+                _ => None,
+            })
+            .map_err(|exp| exp.minimal().to_string())
+        }
+
+        assert_eq!(partial_eval("A && X"), Err("X".into()));
+        assert_eq!(partial_eval("X && A"), Err("X".into()));
+        assert_eq!(partial_eval("X && B"), Ok(false));
+        assert_eq!(partial_eval("X || A"), Ok(true));
+        assert_eq!(partial_eval("B || !X"), Err("!X".into()));
+        assert_eq!(partial_eval("!(A && B)"), Ok(true));
+        assert_eq!(
+            partial_eval("(X || B) && (Y || 0) || !1"),
+            Err("X && Y".into())
+        );
+        assert_eq!(partial_eval("!(X && Y) || Z"), Err("!(X && Y) || Z".into()));
+    }
+
+    #[test]
+    fn simplify() {
+        fn simplify(exp: &str) -> std::string::String {
+            let exp = Exp::from_str(exp).expect("failed to parse expression");
+            let simplified = exp.simplify();
+            assert_eq!(simplified.equivalent(&exp), Some(true));
+            simplified.minimal().to_string()
+        }
+
+        assert_eq!(simplify("A && 1"), "A");
+        assert_eq!(simplify("A && false"), "false");
+        assert_eq!(simplify("A || true"), "true");
+        assert_eq!(simplify("A || 0"), "A");
+        assert_eq!(simplify("!!A"), "A");
+        assert_eq!(simplify("!!!A"), "!A");
+        assert_eq!(simplify("!(A && !B)"), "!A || B");
+        assert_eq!(simplify("!(A || B || !C)"), "!A && !B && C");
+        assert_eq!(simplify("A && A && B"), "A && B");
+        assert_eq!(simplify("A && !A"), "false");
+        assert_eq!(simplify("(A || !A) && B"), "B");
+        assert_eq!(simplify("A && (A || B)"), "A");
+        assert_eq!(simplify("A || (A && B)"), "A");
+        assert_eq!(simplify("(A || B) && (B || A) && C"), "(A || B) && C");
+        assert_eq!(simplify("(A || B) && (A || B || C)"), "A || B");
+        assert_eq!(simplify("(A && B) || (B && A && C) || D"), "(A && B) || D");
+    }
+
+    #[test]
+    fn normal_forms() {
+        fn forms(exp: &str) -> (std::string::String, std::string::String) {
+            let exp = Exp::from_str(exp).expect("failed to parse expression");
+            let (dnf, cnf) = (exp.to_dnf(), exp.to_cnf());
+            assert_eq!(dnf.equivalent(&exp), Some(true));
+            assert_eq!(cnf.equivalent(&exp), Some(true));
+            (dnf.minimal().to_string(), cnf.minimal().to_string())
+        }
+
+        assert_eq!(forms("A"), ("A".into(), "A".into()));
+        assert_eq!(
+            forms("(A || B) && C"),
+            ("(A && C) || (B && C)".into(), "(A || B) && C".into())
+        );
+        assert_eq!(
+            forms("(A && B) || C"),
+            ("(A && B) || C".into(), "(A || C) && (B || C)".into())
+        );
+        assert_eq!(
+            forms("!(A && (B || !C))"),
+            ("!A || (!B && C)".into(), "(!A || !B) && (!A || C)".into())
+        );
+        assert_eq!(
+            forms("(A || B) && (A || !B)"),
+            ("A".into(), "(A || B) && (A || !B)".into())
+        );
+        assert_eq!(forms("A && !A"), ("false".into(), "false".into()));
+        assert_eq!(forms("A || !A || 0"), ("true".into(), "true".into()));
+
+        for exp in [
+            "(A || B) && (C || D) && !(A && D)",
+            "!(A || (B && !C)) || (D && !(!A || C))",
+            "((A && 1) || (B && 0)) && !(C || false)",
+        ] {
+            forms(exp);
+        }
+    }
+
+    #[test]
+    fn equivalent() {
+        let equivalent = |a: &str, b: &str| {
+            Exp::from_str(a)
+                .unwrap()
+                .equivalent(&Exp::from_str(b).unwrap())
+                .unwrap()
+        };
+
+        assert!(equivalent("!(A && B)", "!A || !B"));
+        assert!(equivalent("A && (B || C)", "(A && B) || (A && C)"));
+        // `&&` and `||` share the same precedence
+        assert!(equivalent("A || B && C", "(A || B) && C"));
+        assert!(equivalent("A || 0", "A && true"));
+        assert!(equivalent("A || !A", "1"));
+        assert!(!equivalent("A", "B"));
+        assert!(!equivalent("A && B", "A"));
+
+        // too many assignments to check
+        let vars: Vec<std::string::String> = (0..=MAX_VARS).map(|i| format!("V{}", i)).collect();
+        let (all, max) = (vars.join(" && "), vars[..MAX_VARS].join(" && "));
+        let exp = Exp::from_str(&all).unwrap();
+        assert_eq!(exp.equivalent(&exp), None);
+        let exp = Exp::from_str(&max).unwrap();
+        assert_eq!(exp.equivalent(&exp), Some(true));
+    }
+
     #[test]
     fn parse() {
         fn test(exp: &[Op]) {
@@ -294,21 +809,18 @@ mod tests {
     #[test]
     fn malformed() {
         fn check(exp: &str) {
-            match Exp::from_str(exp) {
-                Ok(val) => {
-                    if val.is_valid() {
-                        panic!(
-                            "expression `{}` was parsed as: `{}` {:?}",
-                            exp, &val, &val.ops
-                        );
-                    } else {
-                        panic!(
-                            "expression `{}` was parsed as an invalid `Exp`: {:?}",
-                            exp, &val.ops
-                        );
-                    }
+            if let Ok(val) = Exp::from_str(exp) {
+                if val.is_valid() {
+                    panic!(
+                        "expression `{}` was parsed as: `{}` {:?}",
+                        exp, &val, &val.ops
+                    );
+                } else {
+                    panic!(
+                        "expression `{}` was parsed as an invalid `Exp`: {:?}",
+                        exp, &val.ops
+                    );
                 }
-                Err(_) => {}
             }
         }
 
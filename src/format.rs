@@ -0,0 +1,210 @@
+//! Formatter for the directives, see [`Formatter`]
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use crate::{Config, Directive, File, Line};
+
+/// Rewrites files with the directives of nested `#if` blocks indented, expressions with
+/// only the required parentheses, see [`Exp::minimal`](crate::exp::Exp::minimal),
+/// and a single space between the parts of a directive
+///
+/// Lines of code, unknown directives and the text after a directive are outputted
+/// by the pre-processor so they are kept untouched, the line ending of the first line
+/// is used for every line
+pub struct Formatter {
+    /// Added before the directives once for each enclosing `#if` block, default 4 spaces
+    pub indent: String,
+}
+
+impl Default for Formatter {
+    fn default() -> Self {
+        Self {
+            indent: "    ".into(),
+        }
+    }
+}
+
+impl Formatter {
+    pub fn format(&self, file: &File, config: &Config) -> String {
+        let data = file.data();
+        let line_ending = match data.find('\n') {
+            Some(end) if data[..end].ends_with('\r') => "\r\n",
+            _ => "\n",
+        };
+
+        let mut output = String::with_capacity(data.len());
+        let mut depth = 0usize;
+        for (i, line) in file.lines().iter().enumerate() {
+            if let Line::Rem(rem) = line {
+                if !rem.starts_with([' ', '\t']) {
+                    output.push(' ');
+                }
+                output.push_str(rem);
+                continue;
+            }
+
+            if i > 0 {
+                output.push_str(line_ending);
+            }
+
+            let (level, directive) = match line {
+                Line::Code(code) | Line::Block { code, .. } | Line::Unknown { line: code, .. } => {
+                    output.push_str(code);
+                    continue;
+                }
+                Line::Rem(_) => unreachable!(),
+                Line::If(_) => {
+                    depth += 1;
+                    (depth - 1, Directive::If)
+                }
+                Line::Elif(_) => (depth.saturating_sub(1), Directive::Elif),
+                Line::Else => (depth.saturating_sub(1), Directive::Else),
+                Line::Endif => {
+                    depth = depth.saturating_sub(1);
+                    (depth, Directive::Endif)
+                }
+                Line::Def(_) => (depth, Directive::Define),
+                Line::Undef(_) => (depth, Directive::Undef),
+                Line::Inc(_) => (depth, Directive::Include),
+            };
+
+            for _ in 0..level {
+                output.push_str(&self.indent);
+            }
+            output.push(config.special_char as char);
+            // aliases are replaced by the first keyword of their directive
+            output.push_str(config.keyword(directive));
+
+            match line {
+                Line::If(exp) | Line::Elif(exp) => {
+                    write!(output, " {}", exp.minimal()).unwrap();
+                }
+                Line::Def(def) | Line::Undef(def) => {
+                    output.push(' ');
+                    output.push_str(def);
+                }
+                Line::Inc(inc) => {
+                    output.push(' ');
+                    output.push(config.include_begin as char);
+                    output.push_str(inc);
+                    output.push(config.include_end as char);
+                }
+                _ => {}
+            }
+        }
+
+        if data.ends_with('\n') {
+            output.push_str(line_ending);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MemoryFileLoader, PreProcessor};
+
+    use super::*;
+
+    fn format(data: &str) -> String {
+        let config = Config::default();
+        Formatter::default().format(&File::parse(data.into(), &config), &config)
+    }
+
+    #[test]
+    fn format_file() {
+        let data = "\
+#if (A && B) || !(C)
+  code
+#if D
+#define   E // comment
+   #elif !(!A)
+#include  \"a.wgsl\"
+        #else
+/*
+#if X
+*/
+#version 450
+        #endif
+#endif // end
+";
+        assert_eq!(
+            format(data),
+            "\
+#if (A && B) || !C
+  code
+    #if D
+        #define E // comment
+    #elif !!A
+        #include \"a.wgsl\"
+    #else
+/*
+#if X
+*/
+#version 450
+    #endif
+#endif // end
+"
+        );
+
+        // already formatted
+        let formatted = format(data);
+        assert_eq!(format(&formatted), formatted);
+
+        assert_eq!(format("#if A\r\nb\r\n#endif"), "#if A\r\nb\r\n#endif");
+        assert_eq!(format(""), "");
+    }
+
+    #[test]
+    fn only_directives_change() {
+        for data in [
+            "a\n#",
+            "a\n#\n",
+            "  #\t",
+            "#if A\n#[x]\n  code  \n#version  450\n\n#endif  ",
+            "#if A\r\n/*\r\n  #if B\r\n*/\r\n\"#endif\"\r\n#endif\r\n",
+            "#if A\n#define B\n#include \"c\"\n#undef B\n#elif C\n#else\n#endif\nend",
+        ] {
+            let file = File::parse(data.into(), &Config::default());
+            let directives: Vec<usize> = file
+                .numbered_lines()
+                .filter(|(_, line)| {
+                    !matches!(
+                        line,
+                        Line::Code(_) | Line::Block { .. } | Line::Rem(_) | Line::Unknown { .. }
+                    )
+                })
+                .map(|(number, _)| number)
+                .collect();
+
+            let formatted = format(data);
+            let lines: Vec<&str> = data.split('\n').collect();
+            let formatted_lines: Vec<&str> = formatted.split('\n').collect();
+            assert_eq!(lines.len(), formatted_lines.len(), "{:?}", data);
+            for (i, (line, formatted_line)) in lines.iter().zip(&formatted_lines).enumerate() {
+                if !directives.contains(&(i + 1)) {
+                    assert_eq!(line, formatted_line, "{:?}", data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn same_output() {
+        let data = "#if A\n  #if !B // rem\nb\n #endif\n#else\nc\n#endif\n";
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert("main.c".into(), data.into());
+        file_loader.files.insert("formatted.c".into(), format(data));
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+
+        for defines in [&[][..], &["A"], &["A", "B"]] {
+            pre_processor.defines = defines.iter().map(|&def| def.into()).collect();
+            let mut output = String::new();
+            pre_processor.process_to_str("main.c", &mut output);
+            let mut formatted = String::new();
+            pre_processor.process_to_str("formatted.c", &mut formatted);
+            assert_eq!(output, formatted);
+        }
+    }
+}
@@ -0,0 +1,495 @@
+//! Include graph of the cached files, see [`PreProcessor::include_graph`]
+
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::fmt::Write;
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::{
+    exp::{Exp, Op},
+    File, Line, PreProcessor,
+};
+
+/// A `#include` directive
+#[derive(Debug, PartialEq, Eq)]
+pub struct Include {
+    /// File with the directive
+    pub from: String,
+    /// Included path
+    pub to: String,
+    /// Line of the directive in `from`, starting at 1
+    pub line: usize,
+    /// Expression that must be true for the include to be reached, `None` when the include
+    /// isn't inside any `#if` block
+    pub condition: Option<String>,
+}
+
+/// Forward and reverse include edges between files
+#[derive(Default, Debug)]
+pub struct IncludeGraph {
+    /// Every file sorted by path, includes that couldn't be loaded are listed as well
+    pub files: Vec<String>,
+    /// Sorted by the including file then by line
+    pub edges: Vec<Include>,
+    forward: HashMap<String, Vec<usize>>,
+    reverse: HashMap<String, Vec<usize>>,
+}
+
+impl IncludeGraph {
+    /// Graph of `files`, the [`Include::condition`]s are only built when `conditions` is set
+    /// since they aren't needed to walk the graph
+    pub(crate) fn new(files: &HashMap<String, Rc<File>>, conditions: bool) -> Self {
+        let mut graph = Self::default();
+
+        let mut paths: Vec<&String> = files.keys().collect();
+        paths.sort();
+
+        for path in paths {
+            let file = &files[path];
+
+            // conditions of each open `#if` chain, `true` when the last branch is an `#else`
+            let mut chains: Vec<(Vec<&Exp>, bool)> = vec![];
+            for (line_count, line) in file.numbered_lines() {
+                match line {
+                    Line::Inc(inc) => graph.edges.push(Include {
+                        from: path.clone(),
+                        to: String::from(*inc),
+                        line: line_count,
+                        condition: if conditions { condition(&chains) } else { None },
+                    }),
+                    Line::If(exp) => chains.push((vec![exp], false)),
+                    Line::Elif(exp) => {
+                        if let Some((conditions, _)) = chains.last_mut() {
+                            conditions.push(exp);
+                        }
+                    }
+                    Line::Else => {
+                        if let Some((_, is_else)) = chains.last_mut() {
+                            *is_else = true;
+                        }
+                    }
+                    Line::Endif => {
+                        chains.pop();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut files: HashSet<&str> = files.keys().map(String::as_str).collect();
+        for (i, edge) in graph.edges.iter().enumerate() {
+            files.insert(&edge.to);
+            graph.forward.entry(edge.from.clone()).or_default().push(i);
+            graph.reverse.entry(edge.to.clone()).or_default().push(i);
+        }
+        graph.files = files.into_iter().map(String::from).collect();
+        graph.files.sort();
+
+        graph
+    }
+
+    /// Files directly included by `path`
+    pub fn includes<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a Include> + 'a {
+        self.forward
+            .get(path)
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.edges[i])
+    }
+
+    /// Files that directly include `path`
+    pub fn included_by<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a Include> + 'a {
+        self.reverse
+            .get(path)
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.edges[i])
+    }
+
+    /// Files included by `path` directly or transitively, sorted by path
+    pub fn dependencies_of(&self, path: &str) -> Vec<&str> {
+        let mut files = self.walk(&[path], false);
+        files.retain(|&file| file != path);
+        files
+    }
+
+    /// Files that include `path` directly or transitively, sorted by path
+    pub fn dependents_of(&self, path: &str) -> Vec<&str> {
+        let mut files = self.walk(&[path], true);
+        files.retain(|&file| file != path);
+        files
+    }
+
+    /// Files that aren't included by any other file, sorted by path
+    pub fn roots(&self) -> Vec<&str> {
+        self.files
+            .iter()
+            .map(String::as_str)
+            .filter(|&path| !self.reverse.contains_key(path))
+            .collect()
+    }
+
+    /// Roots that include any of `paths` directly or transitively, `paths` that aren't included
+    /// by anything, or that aren't in the graph at all, are roots of them selfs
+    ///
+    /// Files in a cycle of includes that nothing else includes have no root, the first file
+    /// of the cycle by path is returned instead
+    pub fn roots_of<'a>(&'a self, paths: &[&'a str]) -> Vec<&'a str> {
+        // every file that includes `paths`, so the includers of each of them are here as well
+        let files = self.walk(paths, true);
+        let index = |path: &str| files.binary_search(&path).ok();
+
+        // groups the files that include each other into components with Kosaraju's algorithm,
+        // first ordering the files by when the walk of their includes is done
+        let mut order = Vec::with_capacity(files.len());
+        let mut visited = vec![false; files.len()];
+        for start in 0..files.len() {
+            if visited[start] {
+                continue;
+            }
+            visited[start] = true;
+            let mut stack = vec![(start, self.includes(files[start]))];
+            while let Some((i, includes)) = stack.last_mut() {
+                let i = *i;
+                match includes.find_map(|edge| index(&edge.to)) {
+                    Some(j) if !visited[j] => {
+                        visited[j] = true;
+                        stack.push((j, self.includes(files[j])));
+                    }
+                    Some(_) => {}
+                    None => {
+                        order.push(i);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+
+        // then walking the includers in the reverse order, each walk is a component
+        let mut components = vec![usize::MAX; files.len()];
+        let mut component_count = 0;
+        for &start in order.iter().rev() {
+            if components[start] != usize::MAX {
+                continue;
+            }
+            components[start] = component_count;
+            let mut stack = vec![start];
+            while let Some(i) = stack.pop() {
+                for edge in self.included_by(files[i]) {
+                    if let Some(j) = index(&edge.from) {
+                        if components[j] == usize::MAX {
+                            components[j] = component_count;
+                            stack.push(j);
+                        }
+                    }
+                }
+            }
+            component_count += 1;
+        }
+
+        // the roots are the first file of each component nothing else includes, a file
+        // without includers is a component by itself
+        let mut included = vec![false; component_count];
+        for (i, &file) in files.iter().enumerate() {
+            for edge in self.included_by(file) {
+                if index(&edge.from).map(|j| components[j]) != Some(components[i]) {
+                    included[components[i]] = true;
+                }
+            }
+        }
+
+        let mut roots = vec![];
+        for (i, &file) in files.iter().enumerate() {
+            let component = components[i];
+            if !included[component] {
+                roots.push(file);
+                // `files` is sorted so the rest of the component comes after
+                included[component] = true;
+            }
+        }
+
+        for &path in paths {
+            if self
+                .files
+                .binary_search_by(|file| file.as_str().cmp(path))
+                .is_err()
+            {
+                roots.push(path);
+            }
+        }
+        roots.sort();
+        roots.dedup();
+        roots
+    }
+
+    /// Visits every file reachable from `paths`, `paths` included
+    fn walk<'a>(&'a self, paths: &[&str], reverse: bool) -> Vec<&'a str> {
+        let edges = if reverse {
+            &self.reverse
+        } else {
+            &self.forward
+        };
+
+        let mut visited: HashSet<&str> = HashSet::new();
+        let mut stack: Vec<&str> = paths
+            .iter()
+            .filter_map(|&path| {
+                let i = self.files.binary_search_by(|file| file.as_str().cmp(path));
+                i.ok().map(|i| self.files[i].as_str())
+            })
+            .collect();
+        while let Some(path) = stack.pop() {
+            if !visited.insert(path) {
+                continue;
+            }
+
+            for &i in edges.get(path).into_iter().flatten() {
+                let edge = &self.edges[i];
+                stack.push(if reverse { &edge.from } else { &edge.to });
+            }
+        }
+
+        let mut files: Vec<&str> = visited.into_iter().collect();
+        files.sort();
+        files
+    }
+
+    /// Exports the graph in the Graphviz DOT language, conditional edges are dashed
+    /// and labeled with their condition
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph includes {\n");
+        for file in &self.files {
+            writeln!(dot, "    \"{}\";", escape(file)).unwrap();
+        }
+        for edge in &self.edges {
+            write!(
+                dot,
+                "    \"{}\" -> \"{}\"",
+                escape(&edge.from),
+                escape(&edge.to)
+            )
+            .unwrap();
+            if let Some(condition) = &edge.condition {
+                write!(dot, " [label=\"{}\", style=dashed]", escape(condition)).unwrap();
+            }
+            dot.push_str(";\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Exports the graph as JSON, in the format:
+    ///
+    /// ```json
+    /// {
+    ///   "files": ["main.wgsl", "utils.wgsl"],
+    ///   "edges": [{ "from": "main.wgsl", "to": "utils.wgsl", "line": 3, "condition": "A" }]
+    /// }
+    /// ```
+    ///
+    /// `condition` is `null` for unconditional includes
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"files\":[");
+        for (i, file) in self.files.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(json, "\"{}\"", escape(file)).unwrap();
+        }
+        json.push_str("],\"edges\":[");
+        for (i, edge) in self.edges.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"from\":\"{}\",\"to\":\"{}\",\"line\":{},\"condition\":",
+                escape(&edge.from),
+                escape(&edge.to),
+                edge.line
+            )
+            .unwrap();
+            match &edge.condition {
+                Some(condition) => write!(json, "\"{}\"}}", escape(condition)).unwrap(),
+                None => json.push_str("null}"),
+            }
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Combines the conditions of the open `#if` chains into a single expression
+fn condition(chains: &[(Vec<&Exp>, bool)]) -> Option<String> {
+    // each term is a condition and if it must be negated
+    let mut terms = vec![];
+    for (conditions, is_else) in chains {
+        // every previous branch wasn't taken
+        if let Some((last, previous)) = conditions.split_last() {
+            terms.extend(previous.iter().map(|exp| (exp, true)));
+            terms.push((last, *is_else));
+        }
+    }
+
+    let mut ops = vec![];
+    for (i, (exp, not)) in terms.into_iter().enumerate() {
+        ops.extend_from_slice(&exp.ops);
+        if not {
+            ops.push(Op::Not);
+        }
+        if i > 0 {
+            ops.push(Op::And);
+        }
+    }
+
+    if ops.is_empty() {
+        None
+    } else {
+        Some(Exp { ops }.to_string())
+    }
+}
+
+/// Escapes `"` and `\` and the control chars, valid for both DOT and JSON strings
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => write!(escaped, "\\u{:04x}", ch as u32).unwrap(),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+impl PreProcessor {
+    /// Loads `roots` and every file they can include, in any branch, then builds the include
+    /// graph of all the cached files, includes that can't be found are kept as edges
+    /// to files without includes of their own
+    pub fn include_graph(&mut self, roots: &[&str]) -> IncludeGraph {
+        self.preload_all(roots);
+        IncludeGraph::new(&self.files, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryFileLoader;
+
+    use super::*;
+
+    #[test]
+    fn include_graph() {
+        let mut file_loader = MemoryFileLoader::default();
+        for (path, data) in [
+            (
+                "pbr.wgsl",
+                "#include \"pbr_types.wgsl\"\n#if A // comment\n#include \"a.wgsl\"\n\
+                #elif B\n#include \"b.wgsl\"\n#else\n#if C\n#endif\n#include \"c.wgsl\"\n#endif",
+            ),
+            ("prepass.wgsl", "#include \"pbr_types.wgsl\""),
+            ("pbr_types.wgsl", "#include \"types.wgsl\""),
+            ("types.wgsl", ""),
+            ("a.wgsl", ""),
+            ("b.wgsl", ""),
+        ] {
+            file_loader.files.insert(path.into(), data.into());
+        }
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let graph = pre_processor.include_graph(&["pbr.wgsl", "prepass.wgsl"]);
+
+        assert_eq!(
+            graph.files,
+            [
+                "a.wgsl",
+                "b.wgsl",
+                "c.wgsl",
+                "pbr.wgsl",
+                "pbr_types.wgsl",
+                "prepass.wgsl",
+                "types.wgsl"
+            ]
+        );
+
+        let includes: Vec<_> = graph
+            .includes("pbr.wgsl")
+            .map(|edge| (edge.to.as_str(), edge.line, edge.condition.as_deref()))
+            .collect();
+        assert_eq!(
+            includes,
+            [
+                ("pbr_types.wgsl", 1, None),
+                ("a.wgsl", 3, Some("A")),
+                ("b.wgsl", 5, Some("(!(A) && B)")),
+                ("c.wgsl", 9, Some("(!(A) && !(B))")),
+            ]
+        );
+
+        assert_eq!(
+            graph.dependents_of("types.wgsl"),
+            ["pbr.wgsl", "pbr_types.wgsl", "prepass.wgsl"]
+        );
+        assert_eq!(
+            graph.dependencies_of("prepass.wgsl"),
+            ["pbr_types.wgsl", "types.wgsl"]
+        );
+        assert_eq!(graph.roots(), ["pbr.wgsl", "prepass.wgsl"]);
+        assert_eq!(graph.roots_of(&["a.wgsl"]), ["pbr.wgsl"]);
+        assert_eq!(
+            graph.roots_of(&["a.wgsl", "unknown.wgsl"]),
+            ["pbr.wgsl", "unknown.wgsl"]
+        );
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph includes {\n"));
+        assert!(dot.contains("    \"pbr.wgsl\" -> \"pbr_types.wgsl\";\n"));
+        assert!(dot.contains("    \"pbr.wgsl\" -> \"a.wgsl\" [label=\"A\", style=dashed];\n"));
+
+        let json = graph.to_json();
+        assert!(json.starts_with(
+            "{\"files\":[\"a.wgsl\",\"b.wgsl\",\"c.wgsl\",\"pbr.wgsl\",\"pbr_types.wgsl\",\"prepass.wgsl\",\"types.wgsl\"],\"edges\":["
+        ));
+        assert!(json.contains(
+            "{\"from\":\"pbr.wgsl\",\"to\":\"b.wgsl\",\"line\":5,\"condition\":\"(!(A) && B)\"}"
+        ));
+        assert!(json.contains(
+            "{\"from\":\"prepass.wgsl\",\"to\":\"pbr_types.wgsl\",\"line\":1,\"condition\":null}"
+        ));
+    }
+
+    #[test]
+    fn roots_of_cycles() {
+        let mut file_loader = MemoryFileLoader::default();
+        for (path, data) in [
+            // a cycle nothing else includes, including a file before it by path
+            ("x.c", "#include \"y.c\"\n#include \"a.h\""),
+            ("y.c", "#include \"x.c\""),
+            ("a.h", "#include \"self.h\""),
+            // a cycle included by a root
+            ("main.c", "#include \"p.h\""),
+            ("p.h", "#include \"q.h\"\n#include \"a.h\""),
+            ("q.h", "#include \"p.h\""),
+            ("self.h", "#include \"self.h\""),
+        ] {
+            file_loader.files.insert(path.into(), data.into());
+        }
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let graph = pre_processor.include_graph(&["x.c", "main.c"]);
+
+        assert_eq!(graph.roots_of(&["a.h"]), ["main.c", "x.c"]);
+        assert_eq!(graph.roots_of(&["q.h"]), ["main.c"]);
+        assert_eq!(graph.roots_of(&["y.c"]), ["x.c"]);
+        assert_eq!(graph.roots_of(&["self.h"]), ["main.c", "x.c"]);
+
+        // only included by itself
+        pre_processor.files.retain(|path, _| path == "self.h");
+        let graph = pre_processor.include_graph(&[]);
+        assert_eq!(graph.roots_of(&["self.h"]), ["self.h"]);
+    }
+}
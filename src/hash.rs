@@ -0,0 +1,55 @@
+//! Stable hashing for content hashes, the results don't depend on the platform or on a random seed
+
+const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// 128 bits FNV-1a hasher
+#[derive(Clone, Copy)]
+pub struct Fnv128(u128);
+
+impl Default for Fnv128 {
+    fn default() -> Self {
+        Self(OFFSET)
+    }
+}
+
+impl Fnv128 {
+    #[inline]
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u128;
+            self.0 = self.0.wrapping_mul(PRIME);
+        }
+    }
+
+    /// Writes the length before the bytes, so `("ab", "c")` and `("a", "bc")` don't collide
+    #[inline]
+    pub fn write_str(&mut self, text: &str) {
+        self.write(&(text.len() as u64).to_le_bytes());
+        self.write(text.as_bytes());
+    }
+
+    #[inline(always)]
+    pub fn finish(&self) -> u128 {
+        self.0
+    }
+}
+
+/// Hashes `bytes` in one go
+pub fn hash(bytes: &[u8]) -> u128 {
+    let mut hasher = Fnv128::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv128() {
+        // reference values of the FNV-1a 128 bits
+        assert_eq!(hash(b""), 0x6c62272e07bb014262b821756295c58d);
+        assert_eq!(hash(b"a"), 0xd228cb696f1a8caf78912b704e4a8964);
+    }
+}
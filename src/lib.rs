@@ -3,7 +3,11 @@
 extern crate alloc;
 
 use alloc::{boxed::Box, rc::Rc, string::String, vec, vec::Vec};
-use core::fmt;
+use core::{
+    fmt,
+    ops::{Deref, Range},
+};
+use std::{sync::OnceLock, time::SystemTime};
 
 use hashbrown::{HashMap, HashSet};
 use smartstring::{Compact, SmartString};
@@ -11,23 +15,95 @@ use smartstring::{Compact, SmartString};
 pub mod exp;
 use exp::{Ctx, Exp};
 
+mod analysis;
+mod depfile;
+pub use depfile::write_depfile;
+#[cfg(feature = "rayon")]
+mod batch;
+#[cfg(feature = "build")]
+pub mod build;
+pub mod format;
+pub mod graph;
+use graph::IncludeGraph;
+pub mod lint;
+mod partial;
+pub mod program;
+
+mod hash;
+
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod sse2;
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use sse2::parse_file_at;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub use sse2::{parse_exp, parse_file};
 
+pub mod scalar;
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+use scalar::parse_file_at;
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub use scalar::{parse_exp, parse_file};
+
+/// Where a parse error is, `line:column` or `at path:line:column` when the path is known
+struct Position<'a> {
+    path: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl fmt::Display for Position<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}:{}", self.line, self.column)
+        } else {
+            write!(f, "at {}:{}:{}", self.path, self.line, self.column)
+        }
+    }
+}
+
+/// Kinds of directives, see [`Config::directives`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Directive {
+    If,
+    Elif,
+    Else,
+    Endif,
+    Define,
+    Undef,
+    Include,
+}
+
 pub struct Config {
     /// Special ASCII character used to define the start of an directive, default is `b'#'`
     /// but is possible to configure to something like `b'@'`, `b'%'` or `b'!'`
     pub special_char: u8,
     /// Single line comment string, default "//"
     pub comment: SmartString<Compact>,
-    // multiline comments are just too problematic
-    // /// Start of a multi-line comment, default "/*"
-    // pub comment_begin: SmartString<Compact>,
-    // /// End of a multi-line comment, default "*/"
-    // pub comment_end: SmartString<Compact>,
+    /// Start of a multi-line comment, default "/*", leave it empty to disable multi-line comments,
+    /// directives inside multi-line comments are treated as lines of code
+    pub comment_begin: SmartString<Compact>,
+    /// End of a multi-line comment, default "*/"
+    pub comment_end: SmartString<Compact>,
+    /// Allow multi-line comments to be nested like in WGSL or Rust, default `false`
+    pub nested_comments: bool,
+    /// Up to 4 ASCII chars that start and end a string or char literal, default "\"'",
+    /// comments inside literals are ignored, leave it empty to disable string literals
+    ///
+    /// Literals can't span multiple lines, a unterminated literal ends with the line
+    pub string_delimiters: SmartString<Compact>,
+    /// Escape char used inside literals, default `Some(b'\\')`
+    pub string_escape: Option<u8>,
+    /// Keywords that follow the `special_char` and the directive they stand for, default
+    /// `if`, `elif`, `else`, `endif`, `define`, `undef` and `include`
+    ///
+    /// Many keywords can map to the same directive, aliases like `elseif` or `elsif` can be added
+    /// with `config.directives.push(("elseif".into(), Directive::Elif))`, each keyword
+    /// is limited to 16 chars and must be followed by a white space, a comment, a `(` or the end
+    /// of the line, a `!` after `if` and `elif` or `include_begin` after `include`, otherwise
+    /// the directive is reported as [`Line::Unknown`]
+    pub directives: Vec<(SmartString<Compact>, Directive)>,
     /// Start of a include path, default `b'\"'`
     pub include_begin: u8,
     /// Delimiter the end of a include path, default "`b'\"'`, make sure to use a ASCII that
@@ -40,14 +116,56 @@ impl Default for Config {
         Self {
             special_char: b'#',
             comment: "//".into(),
-            // comment_begin: "/*".into(),
-            // comment_end: "*/".into(),
+            comment_begin: "/*".into(),
+            comment_end: "*/".into(),
+            nested_comments: false,
+            string_delimiters: "\"'".into(),
+            string_escape: Some(b'\\'),
+            directives: vec![
+                ("if".into(), Directive::If),
+                ("elif".into(), Directive::Elif),
+                ("else".into(), Directive::Else),
+                ("endif".into(), Directive::Endif),
+                ("define".into(), Directive::Define),
+                ("undef".into(), Directive::Undef),
+                ("include".into(), Directive::Include),
+            ],
             include_begin: b'\"',
             include_end: b'\"',
         }
     }
 }
 
+impl Config {
+    /// Feeds every option that changes how files are parsed
+    fn hash(&self, hasher: &mut hash::Fnv128) {
+        hasher.write(&[self.special_char]);
+        hasher.write_str(&self.comment);
+        hasher.write_str(&self.comment_begin);
+        hasher.write_str(&self.comment_end);
+        hasher.write(&[self.nested_comments as u8]);
+        hasher.write_str(&self.string_delimiters);
+        match self.string_escape {
+            Some(escape) => hasher.write(&[1, escape]),
+            None => hasher.write(&[0]),
+        }
+        hasher.write(&(self.directives.len() as u64).to_le_bytes());
+        for (keyword, kind) in &self.directives {
+            hasher.write_str(keyword);
+            hasher.write(&[*kind as u8]);
+        }
+        hasher.write(&[self.include_begin, self.include_end]);
+    }
+
+    /// First keyword of `directive`, used to write directives so aliases are replaced by it
+    fn keyword(&self, directive: Directive) -> &str {
+        self.directives
+            .iter()
+            .find(|(_, kind)| *kind == directive)
+            .map_or("", |(keyword, _)| keyword.as_str())
+    }
+}
+
 #[inline(always)]
 const unsafe fn str_from_raw_parts<'a>(ptr: *const u8, len: usize) -> &'a str {
     core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len))
@@ -58,10 +176,15 @@ const unsafe fn str_from_range<'a>(ptr: *const u8, ptr_end: *const u8) -> &'a st
     str_from_raw_parts(ptr, ptr_end.offset_from(ptr) as usize)
 }
 
-// todo: a code block should reduce the ammount of memory to store it all
 #[derive(Debug, PartialEq, Eq)]
 pub enum Line<'a> {
     Code(&'a str),
+    /// Many consecutive lines of code separated by `'\n'`, only made by [`File::parse`]
+    /// to save memory and callbacks, `code` doesn't include the last line ending
+    Block {
+        code: &'a str,
+        line_count: usize,
+    },
     Rem(&'a str),
     Inc(&'a str),
     Def(&'a str),
@@ -70,12 +193,22 @@ pub enum Line<'a> {
     Elif(Exp<'a>),
     Else,
     Endif,
+    /// Directive that isn't in [`Config::directives`] like `#version 450` or `#pragma once`,
+    /// `line` is the whole line that gets outputted untouched
+    Unknown {
+        line: &'a str,
+        name: &'a str,
+        rest: &'a str,
+    },
 }
 
 impl<'a> fmt::Display for Line<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Line::Code(line) | Line::Rem(line) => write!(f, "{}", line),
+            Line::Code(line)
+            | Line::Block { code: line, .. }
+            | Line::Rem(line)
+            | Line::Unknown { line, .. } => write!(f, "{}", line),
             Line::Inc(path) => write!(f, "#include \"{}\"", path),
             Line::Def(def) => write!(f, "#define {}", def),
             Line::Undef(def) => write!(f, "#undef {}", def),
@@ -87,28 +220,112 @@ impl<'a> fmt::Display for Line<'a> {
     }
 }
 
+/// Numbers the lines, see [`File::numbered_lines`]
+fn numbered<'a, 'b>(lines: &'b [Line<'a>]) -> impl Iterator<Item = (usize, &'b Line<'a>)> {
+    let mut next = 1;
+    lines.iter().map(move |line| match line {
+        Line::Rem(_) => (next - 1, line),
+        Line::Block { line_count, .. } => {
+            next += line_count;
+            (next - line_count, line)
+        }
+        _ => {
+            next += 1;
+            (next - 1, line)
+        }
+    })
+}
+
 pub struct File {
     _data: String,
     // each line self referece str slices of `_data` that's why the lifelime is 'static
     lines: Vec<Line<'static>>,
+    /// Content hash, lazily computed by [`PreProcessor::cache_key`]
+    hash: OnceLock<u128>,
 }
 
 impl File {
     pub fn parse(data: String, config: &Config) -> Self {
+        Self::parse_with_path(data, config, "")
+    }
+
+    /// Like [`File::parse`] but parse errors are reported with `path`, as in
+    /// "unmached `(` at main.c:1:5"
+    pub fn parse_with_path(data: String, config: &Config, path: &str) -> Self {
         let mut lines = vec![];
 
         // safety: `data` will live as long as each line because they are kept
         // inside the same struct inaccessible to the end user
         let borrow = unsafe { &*(&data as *const String) };
-        sse2::parse_file(&borrow, config, |line| lines.push(line));
+        parse_file_at(borrow, path, config, |line| {
+            if let Line::Code(code) = line {
+                let (start, line_count) = match lines.last() {
+                    Some(&Line::Code(prev)) => (prev, 1),
+                    Some(&Line::Block { code, line_count }) => (code, line_count),
+                    _ => ("", 0),
+                };
+
+                // only lines separated by a `'\n'` are merged so the output stays the same
+                let prev_end = start.as_ptr() as usize + start.len();
+                if line_count > 0 && code.as_ptr() as usize == prev_end + 1 {
+                    // safety: both lines are slices of `data` with a `'\n'` in between
+                    let code =
+                        unsafe { str_from_range(start.as_ptr(), code.as_ptr().add(code.len())) };
+                    *lines.last_mut().unwrap() = Line::Block {
+                        code,
+                        line_count: line_count + 1,
+                    };
+                    return;
+                }
+            }
+            lines.push(line);
+        });
+
+        Self {
+            _data: data,
+            lines,
+            hash: OnceLock::new(),
+        }
+    }
 
-        Self { _data: data, lines }
+    #[inline]
+    pub fn lines(&self) -> &[Line<'_>] {
+        &self.lines
+    }
+
+    /// Lines along with the number of the line they start at, text remaining after a directive
+    /// shares the line with it
+    pub fn numbered_lines(&self) -> impl Iterator<Item = (usize, &Line<'_>)> {
+        numbered(self.lines())
+    }
+
+    /// Content of the file as loaded
+    #[inline]
+    pub fn data(&self) -> &str {
+        &self._data
+    }
+
+    /// Stable hash of the file content
+    pub fn hash(&self) -> u128 {
+        *self.hash.get_or_init(|| hash::hash(self._data.as_bytes()))
     }
 }
 
 pub trait FileLoader {
     // todo: return a parsed File with the proper file path
     fn load(&self, path: &str) -> Option<String>;
+
+    /// Last modification time of the file, used by [`PreProcessor::refresh`] to skip the files
+    /// that didn't change, when `None` the file is loaded again and its content compared
+    fn modified(&self, _path: &str) -> Option<SystemTime> {
+        None
+    }
+
+    /// Path of the file as found by the loader like `"shaders/pbr.wgsl"` for `"pbr.wgsl"`,
+    /// used by dependency files, when `None` the path is used as it is
+    fn resolve(&self, _path: &str) -> Option<String> {
+        None
+    }
 }
 
 pub struct DefaultFileLoader {
@@ -160,6 +377,45 @@ impl FileLoader for DefaultFileLoader {
 
         None
     }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        if let Ok(modified) = std::fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            return Some(modified);
+        }
+
+        let mut search_path = String::new();
+        for base_path in &self.search_paths {
+            search_path.clear();
+            search_path.push_str(base_path);
+            search_path.push_str(std::path::MAIN_SEPARATOR_STR);
+            search_path.push_str(path);
+
+            if let Ok(metadata) = std::fs::metadata(&search_path) {
+                return metadata.modified().ok();
+            }
+        }
+
+        None
+    }
+
+    fn resolve(&self, path: &str) -> Option<String> {
+        if std::path::Path::new(path).is_file() {
+            return Some(path.into());
+        }
+
+        for base_path in &self.search_paths {
+            let mut search_path = String::new();
+            search_path.push_str(base_path);
+            search_path.push_str(std::path::MAIN_SEPARATOR_STR);
+            search_path.push_str(path);
+
+            if std::path::Path::new(&search_path).is_file() {
+                return Some(search_path);
+            }
+        }
+
+        None
+    }
 }
 
 pub struct NoFileLoader;
@@ -170,150 +426,388 @@ impl FileLoader for NoFileLoader {
     }
 }
 
+/// Loads files from memory, useful for tests and for sources that doesn't live in the disk
+#[derive(Default)]
+pub struct MemoryFileLoader {
+    pub files: HashMap<String, String>,
+}
+
+impl FileLoader for MemoryFileLoader {
+    fn load(&self, path: &str) -> Option<String> {
+        self.files.get(path).cloned()
+    }
+}
+
 #[derive(Clone, Copy)]
 struct State {
     // file_path: ...
     // line: usize,
     value: bool,
+    /// Value of the block enclosing the current `if`, nested blocks can't be active inside a inactive one
+    parent_value: bool,
+    /// Some branch of the current `if`, `elif`, `else` chain was already taken
+    branch_taken: bool,
     value_flipped_by_else_block: bool,
 }
 
-pub struct PreProcessor {
-    pub config: Config,
-    pub file_loader: Box<dyn FileLoader>,
-    pub files: HashMap<String, Rc<File>>,
+impl State {
+    const ROOT: Self = Self {
+        value: true,
+        parent_value: true,
+        branch_taken: true,
+        value_flipped_by_else_block: true,
+    };
+}
+
+/// Toggleable defines decided while enumerating permutations, see [`PreProcessor::permutations`]
+#[derive(Default)]
+struct Decisions {
+    /// Defines that weren't read, defined or undefined yet
+    undecided: HashSet<SmartString<Compact>>,
+    /// Values of the defines decided by a previous run, in the order they were read
+    path: Vec<(SmartString<Compact>, bool)>,
+    /// Defines decided in this run, in the order they were read
+    taken: Vec<(SmartString<Compact>, bool)>,
+}
+
+/// Set of defines, see [`PreProcessor::defines`]
+pub type DefineSet = HashSet<SmartString<Compact>>;
+
+/// Consecutive outputted lines of a cached file, see [`PreProcessor::process_to_spans`]
+#[derive(Clone)]
+pub struct Span {
+    pub file: Rc<File>,
+    /// Byte range in [`File::data`], line endings between the lines are kept as they are
+    /// in the file, but the line ending of the last line isn't included
+    pub range: Range<usize>,
+}
+
+impl Span {
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.file.data()[self.range.clone()]
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Span")
+            .field("range", &self.range)
+            .field("text", &self.as_str())
+            .finish()
+    }
+}
+
+/// Two spans are equal when they cover the same range of the same cached file
+impl PartialEq for Span {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.file, &other.file) && self.range == other.range
+    }
+}
+
+impl Eq for Span {}
+
+/// Distinct output of a file, see [`PreProcessor::permutations`]
+#[derive(Debug)]
+pub struct Permutation {
+    /// Toggleable defines enabled to get this output, the ones in [`PreProcessor::defines`]
+    /// are always enabled
     pub defines: HashSet<SmartString<Compact>>,
+    /// Each outputted line is followed by a `'\n'`
+    pub output: String,
+    /// Stable FNV-1a hash of `output`
+    pub hash: u128,
+}
+
+/// Per-run evaluation state of the pre-processor, reused between runs to avoid allocations
+///
+/// Every thread processing from the same [`CachedFiles`] needs its own evaluator,
+/// see [`PreProcessor::cached_files`]
+pub struct Evaluator {
     ctx: Ctx,
     state: State,
     state_stack: Vec<State>,
+    include_stack: Vec<SmartString<Compact>>,
     outputted_line_count: usize,
+    decisions: Option<Decisions>,
+    key: Option<hash::Fnv128>,
+    /// Files reached by the last run, see [`PreProcessor::dependencies`]
+    reached: Vec<SmartString<Compact>>,
+    /// Inactive line ranges of the root file, see [`PreProcessor::inactive_ranges`]
+    inactive: Option<Vec<(usize, usize)>>,
 }
 
-impl Default for PreProcessor {
+impl Default for Evaluator {
     fn default() -> Self {
         Self {
-            config: Config::default(),
-            file_loader: Box::new(DefaultFileLoader::default()),
-            files: HashMap::default(),
-            defines: HashSet::with_capacity(32),
             ctx: Ctx::default(),
-            state: State {
-                value: true,
-                value_flipped_by_else_block: true,
-            },
+            state: State::ROOT,
             state_stack: Vec::with_capacity(4),
+            include_stack: Vec::with_capacity(8),
             outputted_line_count: 1,
+            decisions: None,
+            key: None,
+            reached: vec![],
+            inactive: None,
         }
     }
 }
 
-impl PreProcessor {
-    pub fn with_loader(file_loader: impl FileLoader + 'static) -> Self {
+/// Evaluator of a run that records something besides the output, the recordings are
+/// cleared when dropped so a run that panicked doesn't leave them to the next runs
+struct Recording<'a>(&'a mut Evaluator);
+
+impl Drop for Recording<'_> {
+    fn drop(&mut self) {
+        self.0.decisions = None;
+        self.0.key = None;
+        self.0.inactive = None;
+    }
+}
+
+impl core::ops::Deref for Recording<'_> {
+    type Target = Evaluator;
+
+    fn deref(&self) -> &Evaluator {
+        self.0
+    }
+}
+
+impl core::ops::DerefMut for Recording<'_> {
+    fn deref_mut(&mut self) -> &mut Evaluator {
+        self.0
+    }
+}
+
+/// Read only view of the files cached by a [`PreProcessor`], see [`PreProcessor::cached_files`]
+///
+/// Unlike the pre-processor it's `Send + Sync`, so many threads can process different
+/// define sets from the same parsed files, each with its own [`Evaluator`]
+pub struct CachedFiles<'a> {
+    files: HashMap<&'a str, &'a File>,
+}
+
+impl CachedFiles<'_> {
+    /// Processes `path` with `defines` using only the cached files, reaching a file
+    /// that isn't cached panics like a missing file
+    pub fn process(
+        &self,
+        evaluator: &mut Evaluator,
+        path: &str,
+        defines: &HashSet<SmartString<Compact>>,
+        mut f: impl FnMut(&str),
+    ) {
+        self.process_blocks(evaluator, path, defines, |text| {
+            text.split('\n').for_each(&mut f)
+        });
+    }
+
+    /// Like [`CachedFiles::process`] but calls `f` once for a whole [`Line::Block`]
+    pub fn process_blocks(
+        &self,
+        evaluator: &mut Evaluator,
+        path: &str,
+        defines: &HashSet<SmartString<Compact>>,
+        mut f: impl FnMut(&str),
+    ) {
+        evaluator.process(
+            path,
+            defines,
+            &mut |path| self.files.get(path).copied(),
+            &mut |_, line| f(line),
+        );
+    }
+}
+
+pub struct PreProcessor {
+    pub config: Config,
+    pub file_loader: Box<dyn FileLoader>,
+    pub files: HashMap<String, Rc<File>>,
+    pub defines: HashSet<SmartString<Compact>>,
+    evaluator: Evaluator,
+    /// Modification time of the cached files, used by [`PreProcessor::refresh`]
+    modified: HashMap<String, SystemTime>,
+}
+
+impl Default for PreProcessor {
+    fn default() -> Self {
         Self {
-            file_loader: Box::new(file_loader),
-            ..Default::default()
+            config: Config::default(),
+            file_loader: Box::new(DefaultFileLoader::default()),
+            files: HashMap::default(),
+            defines: HashSet::with_capacity(32),
+            evaluator: Evaluator::default(),
+            modified: HashMap::new(),
         }
     }
+}
 
-    pub fn preload(&mut self, path: &str) -> Option<Rc<File>> {
-        match self.files.entry(path.into()) {
-            hashbrown::hash_map::Entry::Occupied(ref entry) => Some(entry.get().clone()),
-            hashbrown::hash_map::Entry::Vacant(entry) => self.file_loader.load(path).map(|data| {
-                entry
-                    .insert(Rc::new(File::parse(data, &self.config)))
-                    .clone()
-            }),
-        }
+/// Loads and parses `path` unless it's already cached, split from [`PreProcessor::preload`]
+/// so the cache can be borrowed along side the [`Evaluator`]
+fn preload(
+    config: &Config,
+    file_loader: &dyn FileLoader,
+    files: &mut HashMap<String, Rc<File>>,
+    modified: &mut HashMap<String, SystemTime>,
+    path: &str,
+) -> Option<Rc<File>> {
+    match files.entry(path.into()) {
+        hashbrown::hash_map::Entry::Occupied(ref entry) => Some(entry.get().clone()),
+        hashbrown::hash_map::Entry::Vacant(entry) => file_loader.load(path).map(|data| {
+            if let Some(time) = file_loader.modified(path) {
+                modified.insert(path.into(), time);
+            }
+            entry
+                .insert(Rc::new(File::parse_with_path(data, config, path)))
+                .clone()
+        }),
     }
+}
 
-    fn process_file(&mut self, file_path: &str, file: &File, f: &mut impl FnMut(&str)) {
+impl Evaluator {
+    fn process_file<H: Deref<Target = File>>(
+        &mut self,
+        file_path: &str,
+        file: &H,
+        load: &mut impl FnMut(&str) -> Option<H>,
+        f: &mut impl FnMut(&H, &str),
+    ) {
         // does the acctual processing recursively
 
         let stack_depth = self.state_stack.len();
 
-        for (line_count, line) in file.lines.iter().enumerate() {
+        for (line_number, line) in file.numbered_lines() {
+            let value = self.state.value;
+
             match line {
-                Line::Code(line) | Line::Rem(line) => {
+                Line::Code(line) | Line::Rem(line) | Line::Unknown { line, .. } => {
                     // default behaviour is to remove lines
                     if self.state.value {
-                        (f)(line);
+                        (f)(file, line);
                         self.outputted_line_count += 1;
                     }
                 }
+                &Line::Block { code, line_count } => {
+                    if self.state.value {
+                        (f)(file, code);
+                        self.outputted_line_count += line_count;
+                    }
+                }
                 Line::Inc(inc) => {
+                    if !self.state.value {
+                        // includes inside inactive blocks are ignored
+                        continue;
+                    }
+
+                    if self.include_stack.iter().any(|path| path == inc) {
+                        panic!(
+                            "recursive include of \"{}\" at {}:{}",
+                            inc, file_path, line_number
+                        );
+                    }
+
                     // load and recursively add theses lines to the current one
-                    if let Some(inc_file) = self.preload(inc) {
-                        self.process_file(inc, inc_file.as_ref(), f);
+                    if let Some(inc_file) = load(inc) {
+                        if let Some(key) = &mut self.key {
+                            key.write(b"i");
+                            key.write(&inc_file.hash().to_le_bytes());
+                        }
+                        if !self.reached.iter().any(|path| path == inc) {
+                            self.reached.push((*inc).into());
+                        }
+                        self.include_stack.push((*inc).into());
+                        self.process_file(inc, &inc_file, load, f);
+                        self.include_stack.pop();
                     } else {
                         panic!(
                             "couldn't find include file \"{}\" at {}:{}",
-                            inc,
-                            file_path,
-                            line_count + 1
+                            inc, file_path, line_number
                         );
                     }
                 }
                 &Line::Def(def) => {
-                    if self.ctx.vars.insert(def.into()) {
-                        // todo: warn about defining the same variable twice
+                    if self.state.value {
+                        self.overwrite(def);
+                        if self.ctx.vars.insert(def.into()) {
+                            // todo: warn about defining the same variable twice
+                        }
                     }
                 }
                 &Line::Undef(def) => {
-                    if !self.ctx.vars.remove(def) {
-                        // todo: warn about undefining a variable that isn't defined
+                    if self.state.value {
+                        self.overwrite(def);
+                        if !self.ctx.vars.remove(def) {
+                            // todo: warn about undefining a variable that isn't defined
+                        }
                     }
                 }
                 Line::If(exp) => {
+                    if self.state.value {
+                        self.decide(exp);
+                    }
                     self.state_stack.push(self.state);
-                    self.state.value = exp.eval(&mut self.ctx);
+                    self.state.parent_value = self.state.value;
+                    self.state.value = self.state.parent_value && exp.eval(&mut self.ctx);
+                    self.state.branch_taken = self.state.value;
                     self.state.value_flipped_by_else_block = false;
+                    if self.state.parent_value {
+                        self.record_condition();
+                    }
                 }
                 Line::Elif(exp) => {
-                    if self.state_stack.len() == 0 {
+                    if self.state_stack.len() <= stack_depth {
                         panic!(
                             "`elif` doesn't have a maching `if` at {}:{}",
-                            file_path,
-                            line_count + 1
+                            file_path, line_number
                         );
                     }
 
                     if self.state.value_flipped_by_else_block {
-                        panic!("`elif` after `else` at {}:{}", file_path, line_count + 1);
+                        panic!("`elif` after `else` at {}:{}", file_path, line_number);
                     }
 
-                    if !self.state.value {
-                        // state still false, evel expression to see if will print the next lines of code
+                    if self.state.parent_value && !self.state.branch_taken {
+                        // no branch was taken yet, evel expression to see if will print the next lines of code
+                        self.decide(exp);
                         self.state.value = exp.eval(&mut self.ctx);
+                        self.state.branch_taken = self.state.value;
+                        self.record_condition();
+                    } else {
+                        self.state.value = false;
                     }
                 }
                 Line::Else => {
-                    if self.state_stack.len() == 0 {
+                    if self.state_stack.len() <= stack_depth {
                         panic!(
                             "`else` doesn't have a maching `if` at {}:{}",
-                            file_path,
-                            line_count + 1
+                            file_path, line_number
                         );
                     }
 
                     if self.state.value_flipped_by_else_block {
-                        panic!("`else` after `else` at {}:{}", file_path, line_count + 1);
+                        panic!("`else` after `else` at {}:{}", file_path, line_number);
                     }
 
-                    self.state.value = !self.state.value;
+                    self.state.value = self.state.parent_value && !self.state.branch_taken;
+                    self.state.branch_taken = true;
                     self.state.value_flipped_by_else_block = true;
                 }
                 Line::Endif => {
-                    if let Some(prev_state) = self.state_stack.pop() {
-                        self.state = prev_state;
+                    // an `endif` can't close a `if` of the file that included this one
+                    if self.state_stack.len() > stack_depth {
+                        self.state = self.state_stack.pop().unwrap();
                     } else {
                         panic!(
                             "`endif` doesn't have a maching `if` at {}:{}",
-                            file_path,
-                            line_count + 1
+                            file_path, line_number
                         );
                     }
                 }
             }
+
+            if value != self.state.value {
+                self.record_inactive(line_number);
+            }
         }
 
         if stack_depth != self.state_stack.len() {
@@ -321,49 +815,359 @@ impl PreProcessor {
         }
     }
 
-    pub fn process(&mut self, path: &str, mut f: impl FnMut(&str)) {
-        if let Some(file) = self.preload(path) {
+    /// Decides the value of the toggleable defines used by `exp` that weren't decided yet,
+    /// only used while enumerating permutations
+    fn decide(&mut self, exp: &Exp) {
+        let Some(decisions) = &mut self.decisions else {
+            return;
+        };
+
+        for op in &exp.ops {
+            if let &exp::Op::Var(var) = op {
+                if !decisions.undecided.remove(var) {
+                    continue;
+                }
+
+                // follow the path of the previous run then fallback to not defined
+                let value = decisions
+                    .path
+                    .get(decisions.taken.len())
+                    .is_some_and(|(_, value)| *value);
+                decisions.taken.push((var.into(), value));
+
+                if value {
+                    self.ctx.vars.insert(var.into());
+                } else {
+                    self.ctx.vars.remove(var);
+                }
+            }
+        }
+    }
+
+    /// Records the value of the condition just evaluated, only used while computing the cache key
+    #[inline]
+    fn record_condition(&mut self) {
+        if let Some(key) = &mut self.key {
+            key.write(&[b'c', self.state.value as u8]);
+        }
+    }
+
+    /// Opens or closes an inactive range after the directive at `line_number` changed
+    /// the state, only for the root file while computing inactive ranges
+    fn record_inactive(&mut self, line_number: usize) {
+        let Some(inactive) = &mut self.inactive else {
+            return;
+        };
+        if self.include_stack.len() != 1 {
+            return;
+        }
+
+        if self.state.value {
+            if let Some((_, end)) = inactive.last_mut() {
+                *end = line_number - 1;
+            }
+        } else {
+            // empty until closed
+            inactive.push((line_number + 1, line_number));
+        }
+    }
+
+    /// Defines or undefines of a toggleable define make its initial value irrelevant
+    fn overwrite(&mut self, def: &str) {
+        if let Some(decisions) = &mut self.decisions {
+            decisions.undecided.remove(def);
+        }
+    }
+
+    fn process<H: Deref<Target = File>>(
+        &mut self,
+        path: &str,
+        defines: &HashSet<SmartString<Compact>>,
+        load: &mut impl FnMut(&str) -> Option<H>,
+        f: &mut impl FnMut(&H, &str),
+    ) {
+        if let Some(file) = load(path) {
             // clear state
             self.ctx.clear();
             self.outputted_line_count = 0;
-            self.state = State {
-                value: true,
-                value_flipped_by_else_block: true,
-            };
+            self.state = State::ROOT;
             self.state_stack.clear();
+            self.include_stack.clear();
+            self.include_stack.push(path.into());
+            self.reached.clear();
+            self.reached.push(path.into());
 
             // include user defines
-            for def in &self.defines {
+            for def in defines {
                 self.ctx.vars.insert(def.clone());
             }
 
             // begin processing files
-            self.process_file(path, file.as_ref(), &mut f);
+            self.process_file(path, &file, load, f);
         } else {
             panic!("file \"{}\" not found", path);
         }
     }
+}
+
+impl PreProcessor {
+    pub fn with_loader(file_loader: impl FileLoader + 'static) -> Self {
+        Self {
+            file_loader: Box::new(file_loader),
+            ..Default::default()
+        }
+    }
+
+    pub fn preload(&mut self, path: &str) -> Option<Rc<File>> {
+        preload(
+            &self.config,
+            self.file_loader.as_ref(),
+            &mut self.files,
+            &mut self.modified,
+            path,
+        )
+    }
+
+    /// Loads `roots` and every file they can include, in any branch, so they can be processed
+    /// with [`PreProcessor::cached_files`]
+    pub fn preload_all(&mut self, roots: &[&str]) {
+        let mut stack: Vec<String> = roots.iter().map(|&root| String::from(root)).collect();
+        let mut visited: HashSet<String> = HashSet::new();
+        while let Some(path) = stack.pop() {
+            if visited.contains(&path) {
+                continue;
+            }
+
+            if let Some(file) = self.preload(&path) {
+                for line in &file.lines {
+                    if let &Line::Inc(inc) = line {
+                        stack.push(inc.into());
+                    }
+                }
+            }
+            visited.insert(path);
+        }
+    }
+
+    /// Removes `path` from the cache so it will be loaded again next time it's needed,
+    /// returns the root files affected by the change, see [`PreProcessor::refresh`]
+    pub fn invalidate(&mut self, path: &str) -> Vec<String> {
+        let roots = self.affected_roots(&[path.into()]);
+        self.files.remove(path);
+        self.modified.remove(path);
+        roots
+    }
+
+    /// Clears the cache, every file will be loaded again next time it's needed
+    pub fn invalidate_all(&mut self) {
+        self.files.clear();
+        self.modified.clear();
+    }
+
+    /// Reloads the cached files that changed, files that the [`FileLoader`] can't find anymore
+    /// are removed from the cache
+    ///
+    /// A file is only loaded again when its modification time changed or when the loader
+    /// doesn't know it, then its content is compared with the cached one
+    ///
+    /// Returns the root files affected by the changes sorted by path, roots are the cached
+    /// files that aren't included by any other cached file, a file that changed is a root
+    /// of itself when nothing includes it, see [`IncludeGraph::roots_of`]
+    pub fn refresh(&mut self) -> Vec<String> {
+        let mut changed = vec![];
+        let mut reloaded = vec![];
+        for (path, file) in &self.files {
+            let modified = self.file_loader.modified(path);
+            if modified.is_some() && modified.as_ref() == self.modified.get(path) {
+                continue;
+            }
+
+            match self.file_loader.load(path) {
+                Some(data) if hash::hash(data.as_bytes()) == file.hash() => {
+                    // touched but the content is the same
+                    if let Some(modified) = modified {
+                        self.modified.insert(path.clone(), modified);
+                    }
+                }
+                Some(data) => {
+                    changed.push(path.clone());
+                    reloaded.push((path.clone(), data, modified));
+                }
+                None => changed.push(path.clone()),
+            }
+        }
+
+        // the includes are walked before replacing the files
+        let roots = self.affected_roots(&changed);
+
+        for path in &changed {
+            self.files.remove(path);
+            self.modified.remove(path);
+        }
+        for (path, data, modified) in reloaded {
+            if let Some(modified) = modified {
+                self.modified.insert(path.clone(), modified);
+            }
+            let file = Rc::new(File::parse_with_path(data, &self.config, &path));
+            self.files.insert(path, file);
+        }
+
+        roots
+    }
+
+    /// Walks the includes of the cached files backwards from `changed`
+    fn affected_roots(&self, changed: &[String]) -> Vec<String> {
+        let changed: Vec<&str> = changed.iter().map(String::as_str).collect();
+        IncludeGraph::new(&self.files, false)
+            .roots_of(&changed)
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Calls `f` for each outputted line
+    pub fn process(&mut self, path: &str, mut f: impl FnMut(&str)) {
+        self.process_blocks(path, |text| text.split('\n').for_each(&mut f));
+    }
+
+    /// Like [`PreProcessor::process`] but calls `f` once for a whole [`Line::Block`],
+    /// with its lines separated by `'\n'`
+    pub fn process_blocks(&mut self, path: &str, mut f: impl FnMut(&str)) {
+        let Self {
+            config,
+            file_loader,
+            files,
+            defines,
+            evaluator,
+            modified,
+        } = self;
+        evaluator.process(
+            path,
+            defines,
+            &mut |path| preload(config, file_loader.as_ref(), files, modified, path),
+            &mut |_, line| f(line),
+        );
+    }
+
+    /// View of the files cached so far that can be shared between threads, load them
+    /// beforehand with [`PreProcessor::preload_all`]
+    ///
+    /// The view borrows the cache instead of owning the files, since they are kept in
+    /// `Rc`s that single threaded users don't pay atomics for. The trade-off is that
+    /// nothing can be loaded while it's alive, reaching an include that wasn't preloaded
+    /// panics like a missing file, and the pre-processor stays borrowed until every
+    /// thread is done with it
+    pub fn cached_files(&self) -> CachedFiles<'_> {
+        CachedFiles {
+            files: self
+                .files
+                .iter()
+                .map(|(path, file)| (path.as_str(), &**file))
+                .collect(),
+        }
+    }
 
     pub fn process_to_str(&mut self, path: &str, string: &mut String) {
-        self.process(path, |text| {
+        self.process_blocks(path, |text| {
             string.push_str(text);
-            string.push_str("\n");
+            string.push('\n');
         });
     }
 
+    /// Outputs spans of the cached files instead of copying each line, consecutive lines
+    /// of the same file are merged into a single span, so writing each span followed
+    /// by a `'\n'` gives the same output as [`PreProcessor::process_to_str`]
+    /// besides `"\r\n"` line endings
+    pub fn process_to_spans(&mut self, path: &str, spans: &mut Vec<Span>) {
+        let Self {
+            config,
+            file_loader,
+            files,
+            defines,
+            evaluator,
+            modified,
+        } = self;
+        evaluator.process(
+            path,
+            defines,
+            &mut |path| preload(config, file_loader.as_ref(), files, modified, path),
+            &mut |file, line| {
+                let start = line.as_ptr() as usize - file.data().as_ptr() as usize;
+                let end = start + line.len();
+
+                if let Some(span) = spans.last_mut() {
+                    // only separated by a line ending
+                    let gap = file.data().get(span.range.end..start);
+                    if Rc::ptr_eq(&span.file, file) && matches!(gap, Some("\n" | "\r\n")) {
+                        span.range.end = end;
+                        return;
+                    }
+                }
+
+                spans.push(Span {
+                    file: file.clone(),
+                    range: start..end,
+                });
+            },
+        );
+    }
+
+    /// Inclusive ranges of the lines of `path` removed with `defines`, without the directives
+    /// that open and close them, meant for editors to grey out inactive code
+    ///
+    /// Runs the same evaluation as [`PreProcessor::process`] without producing any output,
+    /// includes are still followed since they can define what later conditions read
+    pub fn inactive_ranges(
+        &mut self,
+        path: &str,
+        defines: &HashSet<SmartString<Compact>>,
+    ) -> Vec<(usize, usize)> {
+        let Self {
+            config,
+            file_loader,
+            files,
+            evaluator,
+            modified,
+            ..
+        } = self;
+        let mut evaluator = Recording(evaluator);
+        evaluator.inactive = Some(vec![]);
+        evaluator.process(
+            path,
+            defines,
+            &mut |path| preload(config, file_loader.as_ref(), files, modified, path),
+            &mut |_, _| {},
+        );
+
+        let mut ranges = evaluator.inactive.take().unwrap_or_default();
+        ranges.retain(|(start, end)| start <= end);
+        ranges
+    }
+
     pub fn process_to_writer(&mut self, path: &str, mut writer: impl std::io::Write) {
-        self.process(path, |text| {
+        self.process_blocks(path, |text| {
             writeln!(writer, "{}", text).expect("failed to write line");
         });
     }
 
-    fn find_defines_of_file(&mut self, file: &File, defines: &mut HashSet<SmartString<Compact>>) {
+    fn find_defines_of_file(
+        &mut self,
+        file: &File,
+        visited: &mut HashSet<String>,
+        defines: &mut HashSet<SmartString<Compact>>,
+    ) {
         for line in file.lines.iter() {
             match line {
                 Line::Inc(inc) => {
+                    // every include is followed, even the inactive ones, so each file is only
+                    // visited once, that also stops files that include themselves
+                    if !visited.insert((*inc).into()) {
+                        continue;
+                    }
+
                     // load and recursively add theses lines to the current one
                     if let Some(inc_file) = self.preload(inc) {
-                        self.find_defines_of_file(inc_file.as_ref(), defines);
+                        self.find_defines_of_file(inc_file.as_ref(), visited, defines);
                     }
                 }
                 &Line::Def(def) => {
@@ -371,11 +1175,8 @@ impl PreProcessor {
                 }
                 Line::If(exp) | Line::Elif(exp) => {
                     for op in &exp.ops {
-                        match op {
-                            &exp::Op::Var(def) => {
-                                defines.insert(def.into());
-                            }
-                            _ => {}
+                        if let &exp::Op::Var(def) = op {
+                            defines.insert(def.into());
                         }
                     }
                 }
@@ -386,7 +1187,9 @@ impl PreProcessor {
 
     pub fn find_defines_of(&mut self, path: &str, defines: &mut HashSet<SmartString<Compact>>) {
         if let Some(file) = self.preload(path) {
-            self.find_defines_of_file(file.as_ref(), defines);
+            let mut visited = HashSet::new();
+            visited.insert(path.into());
+            self.find_defines_of_file(file.as_ref(), &mut visited, defines);
             // remove constant defines
             defines.remove("0");
             defines.remove("1");
@@ -396,6 +1199,130 @@ impl PreProcessor {
             panic!("file \"{}\" not found", path);
         }
     }
+
+    /// Enumerates the distinct outputs of `path` for every combination of the `toggles` defines,
+    /// usually found with [`PreProcessor::find_defines_of`]
+    ///
+    /// Instead of processing all the `2^n` combinations, a toggle is only branched on when it's
+    /// read by a `#if` or `#elif` that can change the output, so toggles that don't matter
+    /// or are `#define`d before being read are never branched on, combinations that still
+    /// produce the same output are collapsed into the one with fewer defines
+    ///
+    /// Keep in mind the number of runs still grows exponentially with the number
+    /// of toggles that do change the output
+    pub fn permutations(
+        &mut self,
+        path: &str,
+        toggles: &HashSet<SmartString<Compact>>,
+    ) -> Vec<Permutation> {
+        let mut permutations: Vec<Permutation> = vec![];
+        let mut hashes = HashMap::new();
+
+        let Self {
+            config,
+            file_loader,
+            files,
+            defines,
+            evaluator,
+            modified,
+        } = self;
+        let mut evaluator = Recording(evaluator);
+
+        // depth first search over the decisions made by each run
+        let mut paths = vec![vec![]];
+        while let Some(path_taken) = paths.pop() {
+            evaluator.decisions = Some(Decisions {
+                undecided: toggles.clone(),
+                path: path_taken,
+                taken: vec![],
+            });
+
+            let mut output = String::new();
+            evaluator.process(
+                path,
+                defines,
+                &mut |path| preload(config, file_loader.as_ref(), files, modified, path),
+                &mut |_, line| {
+                    output.push_str(line);
+                    output.push('\n');
+                },
+            );
+
+            let decisions = evaluator.decisions.take().unwrap();
+
+            // every define read for the first time in this run fallback to not defined,
+            // so visit the alternatives where they are defined
+            for i in (decisions.path.len()..decisions.taken.len()).rev() {
+                let mut alternative = decisions.taken[..i].to_vec();
+                alternative.push((decisions.taken[i].0.clone(), true));
+                paths.push(alternative);
+            }
+
+            let defines: HashSet<_> = decisions
+                .taken
+                .into_iter()
+                .filter_map(|(def, value)| value.then_some(def))
+                .collect();
+
+            let hash = hash::hash(output.as_bytes());
+            match hashes.entry(hash) {
+                hashbrown::hash_map::Entry::Occupied(entry) => {
+                    // keep the smallest set of defines that gives this output
+                    let permutation: &mut Permutation = &mut permutations[*entry.get()];
+                    if defines.len() < permutation.defines.len() {
+                        permutation.defines = defines;
+                    }
+                }
+                hashbrown::hash_map::Entry::Vacant(entry) => {
+                    entry.insert(permutations.len());
+                    permutations.push(Permutation {
+                        defines,
+                        output,
+                        hash,
+                    });
+                }
+            }
+        }
+
+        permutations
+    }
+
+    /// Stable key of the output of `path`, to cache the outputs or anything compiled from them
+    /// on disk, the same key is computed in any platform and by any run of the same version
+    /// of this crate
+    ///
+    /// Covers the [`Config`], the content of every file reached with the current
+    /// [`PreProcessor::defines`] and the value of every condition evaluated along the way,
+    /// so defines that doesn't change any condition doesn't change the key either,
+    /// no output is produced
+    pub fn cache_key(&mut self, path: &str) -> u128 {
+        let Some(file) = self.preload(path) else {
+            panic!("file \"{}\" not found", path);
+        };
+
+        let mut key = hash::Fnv128::default();
+        key.write_str(env!("CARGO_PKG_VERSION"));
+        self.config.hash(&mut key);
+        key.write(&file.hash().to_le_bytes());
+
+        let Self {
+            config,
+            file_loader,
+            files,
+            defines,
+            evaluator,
+            modified,
+        } = self;
+        let mut evaluator = Recording(evaluator);
+        evaluator.key = Some(key);
+        evaluator.process(
+            path,
+            defines,
+            &mut |path| preload(config, file_loader.as_ref(), files, modified, path),
+            &mut |_, _| {},
+        );
+        evaluator.key.take().unwrap().finish()
+    }
 }
 
 #[cfg(test)]
@@ -404,17 +1331,28 @@ mod tests {
 
     #[test]
     fn basic() {
-        const FILES: &[(&str, usize)] = &[
-            ("benches/files/Native.g.cs", 177),
-            ("benches/files/shader.wgsl", 636),
+        const FILES: &[(&str, usize, usize)] = &[
+            ("benches/files/Native.g.cs", 177, 10),
+            ("benches/files/shader.wgsl", 635, 126),
         ];
 
         let config = Config::default();
 
-        for &(path, line_count) in FILES {
+        for &(path, line_count, block_count) in FILES {
             let input = std::fs::read_to_string(path).expect("file not found");
             let file = File::parse(input, &config);
-            assert_eq!(file.lines.len(), line_count);
+            assert_eq!(file.lines.len(), block_count);
+
+            // blocks keep track of the lines they merged
+            let count: usize = file
+                .lines
+                .iter()
+                .map(|line| match line {
+                    Line::Block { line_count, .. } => *line_count,
+                    _ => 1,
+                })
+                .sum();
+            assert_eq!(count, line_count);
         }
     }
 
@@ -442,6 +1380,396 @@ mod tests {
         }
     }
 
+    #[test]
+    fn defines_of_recursive_include() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "a.c".into(),
+            "#include \"a.c\"\n#include \"b.c\"\n#if A\n#endif".into(),
+        );
+        file_loader
+            .files
+            .insert("b.c".into(), "#include \"a.c\"\n#define B".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let mut defines = HashSet::new();
+        pre_processor.find_defines_of("a.c", &mut defines);
+        assert_eq!(defines, ["A".into(), "B".into()].into_iter().collect());
+    }
+
+    #[test]
+    fn conditionals() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "main.c".into(),
+            "#if 0\n#if 1\nnested\n#endif\n#define HIDDEN\n#include \"missing.h\"\n#endif\n\
+            #if 1\nif\n#elif 1\nelif\n#else\nelse\n#endif\n\
+            #if HIDDEN\nhidden\n#elif 0\nelif\n#else\nelse\n#endif"
+                .into(),
+        );
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let mut output = String::new();
+        pre_processor.process_to_str("main.c", &mut output);
+
+        assert_eq!(output, "if\nelse\n");
+    }
+
+    #[test]
+    fn permutations() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "main.c".into(),
+            "#if A\na\n#endif\n#if B || !B\nalways\n#endif\n#define C\n\
+            #if C && D\ncd\n#endif\n#if E\n#endif"
+                .into(),
+        );
+        file_loader.files.insert("open.c".into(), "#if A\na".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let toggles = ["A", "B", "C", "D", "E"].map(SmartString::from).into();
+        let permutations = pre_processor.permutations("main.c", &toggles);
+
+        let mut outputs: Vec<_> = permutations
+            .iter()
+            .map(|permutation| {
+                assert_eq!(permutation.hash, hash::hash(permutation.output.as_bytes()));
+
+                let mut defines: Vec<_> =
+                    permutation.defines.iter().map(|def| def.as_str()).collect();
+                defines.sort();
+                (defines, permutation.output.as_str())
+            })
+            .collect();
+        outputs.sort();
+
+        assert_eq!(
+            outputs,
+            [
+                (vec![], "always\n"),
+                (vec!["A"], "a\nalways\n"),
+                (vec!["A", "D"], "a\nalways\ncd\n"),
+                (vec!["D"], "always\ncd\n"),
+            ]
+        );
+
+        // a run that panicked doesn't leave its decisions to the next runs
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pre_processor.permutations("open.c", &toggles)
+        }));
+        assert!(result.is_err());
+        assert!(pre_processor.evaluator.decisions.is_none());
+    }
+
+    #[test]
+    fn cache_key() {
+        fn key(inc: &str, defines: &[&str], config: Config) -> u128 {
+            let mut file_loader = MemoryFileLoader::default();
+            file_loader.files.insert(
+                "main.c".into(),
+                "#if A\n#include \"a.h\"\n#elif B\nb\n#endif\n#define B\n#if B\nb\n#endif".into(),
+            );
+            file_loader.files.insert("a.h".into(), inc.into());
+
+            let mut pre_processor = PreProcessor::with_loader(file_loader);
+            pre_processor.config = config;
+            pre_processor.defines = defines.iter().map(|&def| def.into()).collect();
+            pre_processor.cache_key("main.c")
+        }
+
+        let base = key("a", &[], Config::default());
+        assert_eq!(base, key("a", &[], Config::default()));
+
+        // `C` is never read and `B` is defined before the second read
+        assert_eq!(base, key("a", &["C"], Config::default()));
+        assert_eq!(
+            key("a", &["A"], Config::default()),
+            key("a", &["A", "B"], Config::default())
+        );
+
+        assert_ne!(base, key("a", &["A"], Config::default()));
+        assert_ne!(base, key("a", &["B"], Config::default()));
+
+        // `a.h` is only reached when `A` is defined
+        assert_eq!(base, key("other", &[], Config::default()));
+        assert_ne!(
+            key("a", &["A"], Config::default()),
+            key("other", &["A"], Config::default())
+        );
+
+        let config = Config {
+            comment: "--".into(),
+            ..Default::default()
+        };
+        assert_ne!(base, key("a", &[], config));
+    }
+
+    #[test]
+    fn refresh() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct SharedFileLoader(Arc<Mutex<HashMap<String, String>>>);
+
+        impl FileLoader for SharedFileLoader {
+            fn load(&self, path: &str) -> Option<String> {
+                self.0.lock().unwrap().get(path).cloned()
+            }
+        }
+
+        let file_loader = SharedFileLoader::default();
+        for (path, data) in [
+            ("a.c", "#include \"common.h\"\na"),
+            ("b.c", "#include \"mid.h\"\nb"),
+            ("c.c", "c"),
+            ("mid.h", "#include \"common.h\""),
+            ("common.h", "common"),
+        ] {
+            file_loader
+                .0
+                .lock()
+                .unwrap()
+                .insert(path.into(), data.into());
+        }
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader.clone());
+        let mut output = String::new();
+        for path in ["a.c", "b.c", "c.c"] {
+            pre_processor.process_to_str(path, &mut output);
+        }
+
+        assert!(pre_processor.refresh().is_empty());
+
+        // same content doesn't count as a change
+        file_loader
+            .0
+            .lock()
+            .unwrap()
+            .insert("c.c".into(), "c".into());
+        assert!(pre_processor.refresh().is_empty());
+
+        file_loader
+            .0
+            .lock()
+            .unwrap()
+            .insert("common.h".into(), "changed".into());
+        assert_eq!(pre_processor.refresh(), ["a.c", "b.c"]);
+        assert_eq!(pre_processor.files["common.h"].data(), "changed");
+
+        output.clear();
+        pre_processor.process_to_str("b.c", &mut output);
+        assert_eq!(output, "changed\nb\n");
+
+        assert_eq!(pre_processor.invalidate("c.c"), ["c.c"]);
+        assert_eq!(pre_processor.invalidate("mid.h"), ["b.c"]);
+        // not cached yet
+        assert_eq!(pre_processor.invalidate("d.c"), ["d.c"]);
+
+        // a cycle that nothing else includes is represented by its first file
+        for (path, data) in [("x.h", "#include \"y.h\""), ("y.h", "#include \"x.h\"")] {
+            file_loader
+                .0
+                .lock()
+                .unwrap()
+                .insert(path.into(), data.into());
+            pre_processor.preload(path);
+        }
+        assert_eq!(pre_processor.invalidate("y.h"), ["x.h"]);
+
+        pre_processor.invalidate_all();
+        assert!(pre_processor.files.is_empty());
+    }
+
+    #[test]
+    fn spans() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "main.c".into(),
+            "a\r\n\nb\n#include \"inc.h\"\nc\n#if 1 // rem\nd\n#endif\n\n".into(),
+        );
+        file_loader.files.insert("inc.h".into(), "inc\n\n".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let mut spans = vec![];
+        pre_processor.process_to_spans("main.c", &mut spans);
+
+        assert_eq!(spans[0].clone(), spans[0]);
+        assert_ne!(spans[0], spans[1]);
+        assert_eq!(
+            format!("{:?}", spans[1]),
+            "Span { range: 0..4, text: \"inc\\n\" }"
+        );
+
+        let spans: Vec<_> = spans.iter().map(Span::as_str).collect();
+        assert_eq!(spans, ["a\r\n\nb", "inc\n", "c", "// rem\nd", ""]);
+
+        let mut pre_processor = PreProcessor {
+            file_loader: Box::new({
+                let mut file_loader = DefaultFileLoader::default();
+                file_loader.search_paths.push("benches/files/bevy".into());
+                file_loader
+            }),
+            ..Default::default()
+        };
+        pre_processor.defines.insert("VERTEX_UVS".into());
+
+        let mut spans = vec![];
+        pre_processor.process_to_spans("pbr/pbr.wgsl", &mut spans);
+        let mut output = String::new();
+        for span in &spans {
+            output.push_str(span.as_str());
+            output.push('\n');
+        }
+
+        let mut expected = String::new();
+        pre_processor.process_to_str("pbr/pbr.wgsl", &mut expected);
+        assert_eq!(output.replace("\r\n", "\n"), expected);
+        assert!(spans.len() < pre_processor.evaluator.outputted_line_count / 4);
+    }
+
+    #[test]
+    fn shared_cache() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CachedFiles>();
+        assert_send_sync::<Evaluator>();
+
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "main.c".into(),
+            "#if A\n#include \"a.h\"\n#else\nnot a\n#endif\nmain".into(),
+        );
+        file_loader.files.insert("a.h".into(), "a".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        pre_processor.preload_all(&["main.c"]);
+        assert!(pre_processor.files.contains_key("a.h"));
+
+        let files = &pre_processor.cached_files();
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = [vec![], vec!["A"]]
+                .into_iter()
+                .map(|defines| {
+                    scope.spawn(move || {
+                        let defines = defines.into_iter().map(SmartString::from).collect();
+                        let mut evaluator = Evaluator::default();
+                        let mut output = String::new();
+                        files.process(&mut evaluator, "main.c", &defines, |text| {
+                            output.push_str(text);
+                            output.push('\n');
+                        });
+                        output
+                    })
+                })
+                .collect();
+
+            let outputs: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+            assert_eq!(outputs, ["not a\nmain\n", "a\nmain\n"]);
+        });
+    }
+
+    #[test]
+    fn blocks() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader
+            .files
+            .insert("main.c".into(), "a\nb\n#if 1\nc\nd\n#endif\ne".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let mut lines = vec![];
+        pre_processor.process("main.c", |line| lines.push(line.to_string()));
+        assert_eq!(lines, ["a", "b", "c", "d", "e"]);
+
+        let mut blocks = vec![];
+        pre_processor.process_blocks("main.c", |block| blocks.push(block.to_string()));
+        assert_eq!(blocks, ["a\nb", "c\nd", "e"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unmached `(` at inc.c:2:5")]
+    fn parse_error_path() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader
+            .files
+            .insert("main.c".into(), "#include \"inc.c\"".into());
+        file_loader.files.insert("inc.c".into(), "a\n#if (".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        pre_processor.process("main.c", |_| {});
+    }
+
+    #[test]
+    #[should_panic(expected = "`endif` doesn't have a maching `if` at main.c:5")]
+    fn line_numbers() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader
+            .files
+            .insert("main.c".into(), "a\nb\n#if 1 // rem\n#endif\n#endif".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        pre_processor.process("main.c", |_| {});
+    }
+
+    #[test]
+    fn inactive_ranges() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert(
+            "main.c".into(),
+            "#include \"inc.c\"\n#if A\na\n#if B\nb\n#endif\n#elif C\nc\n\nc\n#else\nelse\n#endif\n\
+            #if B\n#endif\n#if !A\nnot a\n#endif"
+                .into(),
+        );
+        file_loader
+            .files
+            .insert("inc.c".into(), "#if D\n#define A\n#endif\nd".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        let mut defines = HashSet::default();
+        assert_eq!(pre_processor.inactive_ranges("main.c", &defines), [(3, 10)]);
+
+        defines.insert("C".into());
+        assert_eq!(
+            pre_processor.inactive_ranges("main.c", &defines),
+            [(3, 6), (12, 12)]
+        );
+
+        // the include defines `A`
+        defines.insert("D".into());
+        assert_eq!(
+            pre_processor.inactive_ranges("main.c", &defines),
+            [(5, 5), (8, 12), (17, 17)]
+        );
+
+        // nothing is recorded by the following runs
+        let mut output = String::new();
+        pre_processor.process_to_str("main.c", &mut output);
+        assert!(pre_processor.evaluator.inactive.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "recursive include")]
+    fn recursive_include() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader
+            .files
+            .insert("main.c".into(), "#include \"main.c\"".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        pre_processor.process("main.c", |_| {});
+    }
+
+    #[test]
+    #[should_panic(expected = "`endif` doesn't have a maching `if` at inc.c:1")]
+    fn endif_of_including_file() {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader
+            .files
+            .insert("main.c".into(), "#if 1\n#include \"inc.c\"".into());
+        file_loader.files.insert("inc.c".into(), "#endif".into());
+
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        pre_processor.process("main.c", |_| {});
+    }
+
     #[test]
     fn bevy() {
         let mut pre_processor = PreProcessor {
@@ -493,9 +1821,14 @@ mod tests {
             assert!(defines.contains(def), "define `{}` not found", def,);
         }
 
+        let mut live_defines = HashSet::with_capacity(32);
+        pre_processor.find_live_defines_of("pbr/pbr.wgsl", &mut live_defines);
+        assert!(live_defines.is_subset(&defines));
+        assert!(live_defines.contains("VERTEX_UVS"));
+
         let mut output = String::with_capacity(32 * 1024 * 1024);
         pre_processor.process_to_str("pbr/pbr.wgsl", &mut output);
 
-        assert_eq!(pre_processor.outputted_line_count, 1219);
+        assert_eq!(pre_processor.evaluator.outputted_line_count, 1216);
     }
 }
@@ -0,0 +1,459 @@
+//! Lints for suspicious conditional structure, see [`PreProcessor::lint`]
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use hashbrown::HashSet;
+
+use crate::{
+    exp::{self, Exp, MAX_VARS},
+    graph::IncludeGraph,
+    File, Line, PreProcessor,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Lint {
+    /// `#elif` or `#else` that can't be taken since the previous conditions of the chain
+    /// cover every case
+    UnreachableBranch,
+    /// Condition equivalent to the one of a previous branch of the same chain
+    DuplicateCondition,
+    /// Condition that's always true like `A || !A`
+    Tautology,
+    /// Condition that's always false like `A && !A`
+    Contradiction,
+    /// `#if` block without any line besides empty ones
+    EmptyBlock,
+    /// Include nested deeper than [`Lints::max_include_depth`]
+    IncludeDepth,
+}
+
+/// Suspicious directive found by a lint
+#[derive(Debug, PartialEq, Eq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub file: String,
+    /// Line of the directive, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}:{}", self.message, self.file, self.line)
+    }
+}
+
+/// Lints to run, all of them are enabled by default
+pub struct Lints {
+    pub unreachable_branch: bool,
+    pub duplicate_condition: bool,
+    pub tautology: bool,
+    pub contradiction: bool,
+    pub empty_block: bool,
+    /// Includes nested deeper than this from a root file are reported, `None` disables
+    /// the lint, default `Some(8)`
+    pub max_include_depth: Option<usize>,
+}
+
+impl Default for Lints {
+    fn default() -> Self {
+        Self {
+            unreachable_branch: true,
+            duplicate_condition: true,
+            tautology: true,
+            contradiction: true,
+            empty_block: true,
+            max_include_depth: Some(8),
+        }
+    }
+}
+
+/// A `#if`, `#elif`, `#else` chain
+struct Chain<'a> {
+    /// Line and condition of each branch, `None` for the `#else`
+    branches: Vec<(usize, Option<&'a Exp<'a>>)>,
+    empty: bool,
+}
+
+impl Lints {
+    /// Lints that only need `file`, reported with `path`
+    pub fn lint_file(&self, path: &str, file: &File, warnings: &mut Vec<Warning>) {
+        let mut chains: Vec<Chain> = vec![];
+        for (line_number, line) in file.numbered_lines() {
+            let content = match line {
+                Line::If(exp) => {
+                    if let Some(chain) = chains.last_mut() {
+                        chain.empty = false;
+                    }
+                    chains.push(Chain {
+                        branches: vec![(line_number, Some(exp))],
+                        empty: true,
+                    });
+                    continue;
+                }
+                Line::Elif(exp) => {
+                    if let Some(chain) = chains.last_mut() {
+                        chain.branches.push((line_number, Some(exp)));
+                    }
+                    continue;
+                }
+                Line::Else => {
+                    if let Some(chain) = chains.last_mut() {
+                        chain.branches.push((line_number, None));
+                    }
+                    continue;
+                }
+                Line::Endif => {
+                    if let Some(chain) = chains.pop() {
+                        self.lint_chain(path, &chain, warnings);
+                    }
+                    continue;
+                }
+                Line::Code(code) | Line::Block { code, .. } => !code.trim().is_empty(),
+                // shares the line with a directive
+                Line::Rem(_) => false,
+                Line::Inc(_) | Line::Def(_) | Line::Undef(_) | Line::Unknown { .. } => true,
+            };
+            if content {
+                if let Some(chain) = chains.last_mut() {
+                    chain.empty = false;
+                }
+            }
+        }
+    }
+
+    fn lint_chain(&self, path: &str, chain: &Chain, warnings: &mut Vec<Warning>) {
+        let mut warn = |lint, line, message: String| {
+            warnings.push(Warning {
+                lint,
+                file: path.into(),
+                line,
+                message,
+            })
+        };
+
+        if self.empty_block && chain.empty {
+            warn(
+                Lint::EmptyBlock,
+                chain.branches[0].0,
+                "empty `if` block".into(),
+            );
+        }
+
+        // truth table of every condition over all the assignments of the variables,
+        // chains with too many variables aren't evaluated
+        let vars = exp::vars(chain.branches.iter().filter_map(|(_, exp)| *exp));
+        if vars.len() > MAX_VARS {
+            return;
+        }
+
+        let rows = 1usize << vars.len();
+        let tables: Vec<Option<Vec<bool>>> = chain
+            .branches
+            .iter()
+            .map(|(_, exp)| exp.map(|exp| exp.truth_table(&vars)))
+            .collect();
+
+        // assignments already taken by a previous branch
+        let mut taken = vec![false; rows];
+        for (i, ((line, exp), table)) in chain.branches.iter().zip(&tables).enumerate() {
+            let directive = if table.is_some() {
+                if i == 0 {
+                    "if"
+                } else {
+                    "elif"
+                }
+            } else {
+                "else"
+            };
+            let reachable = match table {
+                Some(table) => (0..rows).any(|row| table[row] && !taken[row]),
+                None => taken.contains(&false),
+            };
+
+            if let Some(table) = table {
+                let duplicate = tables[..i]
+                    .iter()
+                    .position(|other| other.as_ref() == Some(table));
+                // `#if 0` and `#if 1` are on purpose
+                let constant = exp.is_some_and(|exp| exp.ops.len() == 1);
+                if !table.contains(&true) {
+                    if self.contradiction && !constant {
+                        warn(
+                            Lint::Contradiction,
+                            *line,
+                            format!("`{}` condition is always false", directive),
+                        );
+                    }
+                } else if let Some(duplicate) = duplicate {
+                    if self.duplicate_condition {
+                        warn(
+                            Lint::DuplicateCondition,
+                            *line,
+                            format!(
+                                "`{}` condition is the same as the one at line {}",
+                                directive, chain.branches[duplicate].0
+                            ),
+                        );
+                    }
+                } else if !table.contains(&false) {
+                    if self.tautology && !constant {
+                        warn(
+                            Lint::Tautology,
+                            *line,
+                            format!("`{}` condition is always true", directive),
+                        );
+                    }
+                } else if !reachable && self.unreachable_branch {
+                    warn(
+                        Lint::UnreachableBranch,
+                        *line,
+                        format!(
+                            "`{}` is never taken, previous conditions cover it",
+                            directive
+                        ),
+                    );
+                }
+
+                for (taken, &value) in taken.iter_mut().zip(table) {
+                    *taken |= value;
+                }
+            } else if !reachable && self.unreachable_branch {
+                warn(
+                    Lint::UnreachableBranch,
+                    *line,
+                    "`else` is never taken, previous conditions cover every case".into(),
+                );
+            }
+        }
+    }
+
+    /// Reports includes nested deeper than [`Lints::max_include_depth`] from `roots`
+    pub fn lint_includes(&self, graph: &IncludeGraph, roots: &[&str], warnings: &mut Vec<Warning>) {
+        let Some(max_depth) = self.max_include_depth else {
+            return;
+        };
+
+        // each include is reported once, at the first depth it's found too deep
+        let mut reported = HashSet::new();
+        let mut visited = HashSet::new();
+        for &root in roots {
+            let mut stack = vec![root];
+            self.walk_includes(
+                graph,
+                &mut stack,
+                max_depth,
+                &mut visited,
+                &mut reported,
+                warnings,
+            );
+        }
+    }
+
+    fn walk_includes<'a>(
+        &self,
+        graph: &'a IncludeGraph,
+        stack: &mut Vec<&'a str>,
+        max_depth: usize,
+        visited: &mut HashSet<(&'a str, usize)>,
+        reported: &mut HashSet<(&'a str, usize)>,
+        warnings: &mut Vec<Warning>,
+    ) {
+        let path = stack[stack.len() - 1];
+        // a file reached again at the same depth includes the same files at the same depths,
+        // so files included from many places are walked once per depth instead of once per path
+        if !visited.insert((path, stack.len())) {
+            return;
+        }
+
+        for include in graph.includes(path) {
+            // recursive includes are reported by the pre-processor
+            if stack.contains(&include.to.as_str()) {
+                continue;
+            }
+
+            if stack.len() > max_depth {
+                if reported.insert((include.from.as_str(), include.line)) {
+                    warnings.push(Warning {
+                        lint: Lint::IncludeDepth,
+                        file: include.from.clone(),
+                        line: include.line,
+                        message: format!(
+                            "include of \"{}\" is nested {} deep, more than {} from \"{}\"",
+                            include.to,
+                            stack.len(),
+                            max_depth,
+                            stack[0]
+                        ),
+                    });
+                }
+                continue;
+            }
+
+            stack.push(&include.to);
+            self.walk_includes(graph, stack, max_depth, visited, reported, warnings);
+            stack.pop();
+        }
+    }
+}
+
+impl PreProcessor {
+    /// Runs `lints` over `roots` and every file they can include, warnings are sorted
+    /// by file and line
+    pub fn lint(&mut self, roots: &[&str], lints: &Lints) -> Vec<Warning> {
+        let graph = self.include_graph(roots);
+
+        let mut warnings = vec![];
+        let mut paths: HashSet<&str> = roots.iter().copied().collect();
+        for root in roots {
+            paths.extend(graph.dependencies_of(root));
+        }
+        for path in paths {
+            if let Some(file) = self.files.get(path) {
+                lints.lint_file(path, file, &mut warnings);
+            }
+        }
+        lints.lint_includes(&graph, roots, &mut warnings);
+
+        warnings.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryFileLoader;
+
+    use super::*;
+
+    fn lint(data: &str, lints: &Lints) -> Vec<(Lint, usize)> {
+        let mut file_loader = MemoryFileLoader::default();
+        file_loader.files.insert("main.c".into(), data.into());
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+        pre_processor
+            .lint(&["main.c"], lints)
+            .into_iter()
+            .map(|warning| (warning.lint, warning.line))
+            .collect()
+    }
+
+    #[test]
+    fn conditions() {
+        let lints = Lints::default();
+        assert_eq!(lint("#if A\na\n#elif B\nb\n#else\nc\n#endif", &lints), []);
+
+        assert_eq!(
+            lint(
+                "#if A\na\n#elif !A\nb\n#else\nc\n#endif\n\
+                #if A && B\na\n#elif A\nb\n#elif B && A\nc\n#endif\n\
+                #if A && !A\na\n#elif A || !A\nb\n#elif B\nc\n#endif\n\
+                #if A || B\na\n#elif B && !A\nb\n#endif",
+                &lints
+            ),
+            [
+                (Lint::UnreachableBranch, 5),
+                (Lint::DuplicateCondition, 12),
+                (Lint::Contradiction, 15),
+                (Lint::Tautology, 17),
+                (Lint::UnreachableBranch, 19),
+                (Lint::UnreachableBranch, 24),
+            ]
+        );
+
+        // constants aren't variables, alone they are on purpose
+        assert_eq!(
+            lint(
+                "#if 0\na\n#endif\n#if 1\na\n#endif\n#if A && 0\na\n#endif\n#if 1 || A\na\n#endif",
+                &lints
+            ),
+            [(Lint::Contradiction, 7), (Lint::Tautology, 10)]
+        );
+
+        let lints = Lints {
+            unreachable_branch: false,
+            contradiction: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            lint(
+                "#if A && !A\na\n#elif A\nb\n#elif A || !A\nb\n#else\nc\n#endif",
+                &lints
+            ),
+            [(Lint::Tautology, 5)]
+        );
+    }
+
+    #[test]
+    fn empty_blocks() {
+        let data = "#if A\n\n#else\n#endif\n#if B\n#if C\na\n#endif\n#endif\n#if D // rem\n#endif";
+        assert_eq!(
+            lint(data, &Lints::default()),
+            [(Lint::EmptyBlock, 1), (Lint::EmptyBlock, 10)]
+        );
+
+        let lints = Lints {
+            empty_block: false,
+            ..Default::default()
+        };
+        assert_eq!(lint(data, &lints), []);
+    }
+
+    #[test]
+    fn include_depth() {
+        let mut file_loader = MemoryFileLoader::default();
+        for (path, data) in [
+            ("main.c", "#include \"a.c\"\n#include \"c.c\""),
+            ("a.c", "#include \"b.c\""),
+            ("b.c", "#if X\n#include \"c.c\"\n#endif"),
+            ("c.c", "#include \"d.c\""),
+            ("d.c", ""),
+        ] {
+            file_loader.files.insert(path.into(), data.into());
+        }
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+
+        let lints = Lints {
+            max_include_depth: Some(3),
+            ..Default::default()
+        };
+        let warnings = pre_processor.lint(&["main.c"], &lints);
+        assert_eq!(
+            warnings,
+            [Warning {
+                lint: Lint::IncludeDepth,
+                file: "c.c".into(),
+                line: 1,
+                message: "include of \"d.c\" is nested 4 deep, more than 3 from \"main.c\"".into(),
+            }]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "include of \"d.c\" is nested 4 deep, more than 3 from \"main.c\" at c.c:1"
+        );
+
+        let lints = Lints {
+            max_include_depth: None,
+            ..Default::default()
+        };
+        assert_eq!(pre_processor.lint(&["main.c"], &lints), []);
+
+        // every file is included twice, so there are 2^32 paths to the last one
+        let mut file_loader = MemoryFileLoader::default();
+        for i in 0..32 {
+            let next = format!("#include \"a{}.c\"\n#include \"b{}.c\"", i, i);
+            file_loader.files.insert(format!("{}.c", i), next);
+            let back = format!("#include \"{}.c\"", i + 1);
+            file_loader.files.insert(format!("a{}.c", i), back.clone());
+            file_loader.files.insert(format!("b{}.c", i), back);
+        }
+        file_loader.files.insert("32.c".into(), "".into());
+        let mut pre_processor = PreProcessor::with_loader(file_loader);
+
+        let lints = Lints {
+            max_include_depth: Some(64),
+            ..Default::default()
+        };
+        assert_eq!(pre_processor.lint(&["0.c"], &lints), []);
+    }
+}
@@ -0,0 +1,460 @@
+//! Partial evaluation of a file when only some defines are known,
+//! see [`PreProcessor::partial_process`]
+
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+use hashbrown::HashMap;
+use smartstring::{Compact, SmartString};
+
+use crate::{
+    exp::{Exp, Op},
+    preload, Config, DefineSet, Directive, File, Line, PreProcessor,
+};
+
+/// A `#if`, `#elif`, `#else` chain
+struct Chain {
+    /// Region enclosing the chain, `Some(true)` when its lines are always outputted,
+    /// `None` when it depends on the unknown defines and `Some(false)` when removed
+    parent: Option<bool>,
+    /// Some branch is taken whenever the previous ones aren't
+    taken: bool,
+    /// Output line of the re-emitted `#if`, if any branch kept a condition
+    emitted: Option<usize>,
+    value_flipped_by_else_block: bool,
+}
+
+struct Partial<'c> {
+    /// Directives are written with its `special_char` and keywords
+    config: &'c Config,
+    /// Known defines and their value, missing defines are unknown
+    vars: HashMap<SmartString<Compact>, bool>,
+    /// Current region, same meaning as [`Chain::parent`]
+    active: Option<bool>,
+    chains: Vec<Chain>,
+    include_stack: Vec<SmartString<Compact>>,
+    output: Vec<String>,
+}
+
+impl Partial<'_> {
+    /// Writes `directive` like the [`Formatter`](crate::format::Formatter) does
+    fn directive(&self, directive: Directive, arg: Option<&dyn fmt::Display>) -> String {
+        let mut line = String::new();
+        line.push(self.config.special_char as char);
+        line.push_str(self.config.keyword(directive));
+        if let Some(arg) = arg {
+            write!(line, " {}", arg).unwrap();
+        }
+        line
+    }
+
+    /// Folds the known defines of `exp`, what is left is simplified
+    fn fold<'a>(&self, exp: &Exp<'a>) -> Result<bool, Exp<'a>> {
+        let exp = match exp.partial_eval(|var| self.vars.get(var).copied()) {
+            Ok(value) => return Ok(value),
+            Err(exp) => exp.simplify(),
+        };
+        match exp.ops[..] {
+            // like `X || !X`
+            [Op::Var("true")] => Ok(true),
+            [Op::Var("false")] => Ok(false),
+            _ => Err(exp),
+        }
+    }
+
+    /// Outputs a `#define` or `#undef` that depends on the unknown defines, the define
+    /// becomes unknown
+    fn overwrite(&mut self, directive: Directive, def: &str) {
+        self.output.push(self.directive(directive, Some(&def)));
+
+        if let Some(value) = self.vars.remove(def) {
+            // the other branches still need the value it had before the outermost
+            // re-emitted `#if` as it was folded until now
+            let at = self
+                .chains
+                .iter()
+                .find_map(|chain| chain.emitted)
+                .expect("unknown region outside of a `if` block");
+            let directive = if value {
+                Directive::Define
+            } else {
+                Directive::Undef
+            };
+            let line = self.directive(directive, Some(&def));
+            self.output.insert(at, line);
+            for chain in &mut self.chains {
+                if let Some(emitted) = &mut chain.emitted {
+                    if *emitted >= at {
+                        *emitted += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Enters a branch with `condition`, `Ok(true)` for `#else`
+    fn branch(&mut self, condition: Result<bool, Exp>) {
+        let chain = self.chains.last_mut().unwrap();
+        if chain.parent == Some(false) || chain.taken {
+            self.active = Some(false);
+            return;
+        }
+
+        match condition {
+            Ok(false) => self.active = Some(false),
+            Ok(true) => {
+                chain.taken = true;
+                if chain.emitted.is_some() {
+                    // taken only if every re-emitted condition is false
+                    let line = self.directive(Directive::Else, None);
+                    self.output.push(line);
+                    self.active = None;
+                } else {
+                    self.active = chain.parent;
+                }
+            }
+            Err(exp) => {
+                let directive = if chain.emitted.is_some() {
+                    Directive::Elif
+                } else {
+                    chain.emitted = Some(self.output.len());
+                    Directive::If
+                };
+                let line = self.directive(directive, Some(&exp));
+                self.output.push(line);
+                self.active = None;
+            }
+        }
+    }
+
+    fn process_file(
+        &mut self,
+        file_path: &str,
+        file: &Rc<File>,
+        load: &mut impl FnMut(&str) -> Option<Rc<File>>,
+    ) {
+        let stack_depth = self.chains.len();
+
+        for (line_number, line) in file.numbered_lines() {
+            match line {
+                Line::Code(text)
+                | Line::Block { code: text, .. }
+                | Line::Rem(text)
+                | Line::Unknown { line: text, .. } => {
+                    if self.active != Some(false) {
+                        self.output.push(String::from(*text));
+                    }
+                }
+                Line::Inc(inc) => {
+                    if self.active == Some(false) {
+                        continue;
+                    }
+
+                    if self.include_stack.iter().any(|path| path == inc) {
+                        panic!(
+                            "recursive include of \"{}\" at {}:{}",
+                            inc, file_path, line_number
+                        );
+                    }
+
+                    if let Some(inc_file) = load(inc) {
+                        self.include_stack.push((*inc).into());
+                        self.process_file(inc, &inc_file, load);
+                        self.include_stack.pop();
+                    } else {
+                        panic!(
+                            "couldn't find include file \"{}\" at {}:{}",
+                            inc, file_path, line_number
+                        );
+                    }
+                }
+                &Line::Def(def) | &Line::Undef(def) => match self.active {
+                    Some(true) => {
+                        self.vars.insert(def.into(), matches!(line, Line::Def(_)));
+                    }
+                    None if matches!(line, Line::Def(_)) => self.overwrite(Directive::Define, def),
+                    None => self.overwrite(Directive::Undef, def),
+                    Some(false) => {}
+                },
+                Line::If(exp) => {
+                    let condition = match self.active {
+                        Some(false) => Ok(false),
+                        _ => self.fold(exp),
+                    };
+                    self.chains.push(Chain {
+                        parent: self.active,
+                        taken: false,
+                        emitted: None,
+                        value_flipped_by_else_block: false,
+                    });
+                    self.branch(condition);
+                }
+                Line::Elif(exp) => {
+                    if self.chains.len() <= stack_depth {
+                        panic!(
+                            "`elif` doesn't have a maching `if` at {}:{}",
+                            file_path, line_number
+                        );
+                    }
+
+                    let chain = self.chains.last().unwrap();
+                    if chain.value_flipped_by_else_block {
+                        panic!("`elif` after `else` at {}:{}", file_path, line_number);
+                    }
+
+                    let condition = match chain.parent {
+                        Some(false) => Ok(false),
+                        _ => self.fold(exp),
+                    };
+                    self.branch(condition);
+                }
+                Line::Else => {
+                    if self.chains.len() <= stack_depth {
+                        panic!(
+                            "`else` doesn't have a maching `if` at {}:{}",
+                            file_path, line_number
+                        );
+                    }
+
+                    let chain = self.chains.last_mut().unwrap();
+                    if chain.value_flipped_by_else_block {
+                        panic!("`else` after `else` at {}:{}", file_path, line_number);
+                    }
+                    chain.value_flipped_by_else_block = true;
+
+                    self.branch(Ok(true));
+                }
+                Line::Endif => {
+                    if self.chains.len() <= stack_depth {
+                        panic!(
+                            "`endif` doesn't have a maching `if` at {}:{}",
+                            file_path, line_number
+                        );
+                    }
+
+                    let chain = self.chains.pop().unwrap();
+                    if chain.emitted.is_some() {
+                        let line = self.directive(Directive::Endif, None);
+                        self.output.push(line);
+                    }
+                    self.active = chain.parent;
+                }
+            }
+        }
+
+        if stack_depth != self.chains.len() {
+            panic!("some `if` block is open in file {}", file_path);
+        }
+    }
+}
+
+impl PreProcessor {
+    /// Processes `path` knowing only some defines, the ones in `defined` are defined
+    /// and the ones in `undefined` aren't, any other define is unknown
+    ///
+    /// Conditions decided by the known defines are folded, the others are simplified,
+    /// see [`Exp::simplify`], and outputted as `#if` blocks along with the `#define`s
+    /// and `#undef`s inside them, includes are inlined, so processing the output with
+    /// the unknown defines gives the same output as processing `path` with all the defines
+    ///
+    /// Directives are outputted with [`Config::special_char`] and the first keyword of each
+    /// directive in [`Config::directives`]
+    pub fn partial_process(
+        &mut self,
+        path: &str,
+        defined: &DefineSet,
+        undefined: &DefineSet,
+        mut f: impl FnMut(&str),
+    ) {
+        let mut vars = HashMap::new();
+        // same constants as `Ctx`
+        for (var, value) in [("true", true), ("1", true), ("0", false), ("false", false)] {
+            vars.insert(var.into(), value);
+        }
+        for def in undefined {
+            vars.insert(def.clone(), false);
+        }
+        for def in defined {
+            vars.insert(def.clone(), true);
+        }
+
+        let Self {
+            config,
+            file_loader,
+            files,
+            modified,
+            ..
+        } = self;
+        let mut load = |path: &str| preload(config, file_loader.as_ref(), files, modified, path);
+
+        let mut partial = Partial {
+            config,
+            vars,
+            active: Some(true),
+            chains: vec![],
+            include_stack: vec![path.into()],
+            output: vec![],
+        };
+        if let Some(file) = load(path) {
+            partial.process_file(path, &file, &mut load);
+        } else {
+            panic!("file \"{}\" not found", path);
+        }
+
+        for line in &partial.output {
+            f(line);
+        }
+    }
+
+    pub fn partial_process_to_str(
+        &mut self,
+        path: &str,
+        defined: &DefineSet,
+        undefined: &DefineSet,
+        string: &mut String,
+    ) {
+        self.partial_process(path, defined, undefined, |text| {
+            string.push_str(text);
+            string.push('\n');
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryFileLoader;
+
+    use super::*;
+
+    fn with_files(files: &[(&str, &str)]) -> PreProcessor {
+        let mut file_loader = MemoryFileLoader::default();
+        for (path, data) in files {
+            file_loader.files.insert((*path).into(), (*data).into());
+        }
+        PreProcessor::with_loader(file_loader)
+    }
+
+    fn set(defines: &[&str]) -> DefineSet {
+        defines.iter().map(|&def| def.into()).collect()
+    }
+
+    #[test]
+    fn fold() {
+        let mut pre_processor = with_files(&[
+            (
+                "main.c",
+                "#include \"inc.c\"\n#if A && X\nax\n#elif B\nb\n#else\nelse\n#endif\n\
+                #if VULKAN\nvk\n#else\ngl\n#endif",
+            ),
+            ("inc.c", "#if PLATFORM\n#define VULKAN\n#endif"),
+        ]);
+
+        let mut output = String::new();
+        pre_processor.partial_process_to_str(
+            "main.c",
+            &set(&["A", "PLATFORM"]),
+            &set(&["B"]),
+            &mut output,
+        );
+        assert_eq!(output, "#if X\nax\n#else\nelse\n#endif\nvk\n");
+
+        output.clear();
+        pre_processor.partial_process_to_str("main.c", &set(&["B"]), &set(&[]), &mut output);
+        assert_eq!(
+            output,
+            "#if PLATFORM\n#define VULKAN\n#endif\n#if (A && X)\nax\n#else\nb\n#endif\n\
+            #if VULKAN\nvk\n#else\ngl\n#endif\n"
+        );
+    }
+
+    #[test]
+    fn config_directives() {
+        let mut pre_processor = with_files(&[(
+            "main.c",
+            "@if A && X\nax\n@elseif B\nb\n@else\nelse\n@endif\n\
+            @if Y\n@define Z\n@undef W\n@endif",
+        )]);
+        pre_processor.config.special_char = b'@';
+        // aliases are replaced by the first keyword
+        pre_processor
+            .config
+            .directives
+            .push(("elseif".into(), Directive::Elif));
+
+        let mut output = String::new();
+        pre_processor.partial_process_to_str("main.c", &set(&["A"]), &set(&["W"]), &mut output);
+        assert_eq!(
+            output,
+            "@if X\nax\n@elif B\nb\n@else\nelse\n@endif\n\
+            @undef W\n@if Y\n@define Z\n@undef W\n@endif\n"
+        );
+    }
+
+    #[test]
+    fn same_output() {
+        let main = "\
+#if A
+#define B
+#endif
+#if B && !C
+b
+#undef A
+#elif D // rem
+d
+#include \"inc.c\"
+#else
+else
+#endif
+#if A || D
+a or d
+#endif";
+        let inc = "#if !A\n#define C\n#endif\ninc";
+
+        let defines = ["A", "B", "C", "D"];
+        let mut pre_processor = with_files(&[("main.c", main), ("inc.c", inc)]);
+
+        // every define is either defined, undefined or unknown
+        for known in 0..3usize.pow(defines.len() as u32) {
+            let (mut defined, mut undefined, mut unknown) = (vec![], vec![], vec![]);
+            let mut n = known;
+            for def in defines {
+                [&mut defined, &mut undefined, &mut unknown][n % 3].push(def);
+                n /= 3;
+            }
+
+            let mut partial = String::new();
+            pre_processor.partial_process_to_str(
+                "main.c",
+                &set(&defined),
+                &set(&undefined),
+                &mut partial,
+            );
+            let mut partial_pre_processor = with_files(&[("partial.c", &partial)]);
+
+            for assignment in 0..1 << unknown.len() {
+                // the known defines aren't needed anymore
+                let mut defines = set(&[]);
+                for (i, def) in unknown.iter().enumerate() {
+                    if assignment & (1 << i) != 0 {
+                        defines.insert((*def).into());
+                    }
+                }
+
+                pre_processor.defines = defines.clone();
+                pre_processor.defines.extend(set(&defined));
+                let mut expected = String::new();
+                pre_processor.process_to_str("main.c", &mut expected);
+
+                partial_pre_processor.defines = defines;
+                let mut output = String::new();
+                partial_pre_processor.process_to_str("partial.c", &mut output);
+
+                assert_eq!(
+                    output, expected,
+                    "defined {:?} undefined {:?} partial:\n{}",
+                    defined, undefined, partial
+                );
+            }
+        }
+    }
+}
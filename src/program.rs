@@ -0,0 +1,576 @@
+//! Files compiled into a flat program for fast re-evaluation, see [`PreProcessor::compile`]
+
+use alloc::{boxed::Box, format, rc::Rc, string::String, vec, vec::Vec};
+
+use hashbrown::HashMap;
+use smallvec::SmallVec;
+use smartstring::{Compact, SmartString};
+
+use crate::{
+    exp::{Exp, Op},
+    DefineSet, File, Line, PreProcessor,
+};
+
+/// Defines and undefines tested by a condition over a 64 bits word of the program vars
+#[derive(Debug, Clone, Copy)]
+struct Mask {
+    word: u32,
+    set: u64,
+    clear: u64,
+}
+
+#[derive(Debug)]
+enum Cond {
+    /// Every `set` bit is defined and every `clear` bit isn't, like `A && !B`
+    All(Vec<Mask>),
+    /// Some `set` bit is defined or some `clear` bit isn't, like `A || !B`
+    Any(Vec<Mask>),
+    /// Any other expression, evaluated with a stack like [`Exp::eval`]
+    Ops(Vec<VarOp>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum VarOp {
+    Var(u32),
+    And,
+    Or,
+    Not,
+}
+
+#[derive(Debug)]
+enum Inst {
+    /// Outputted line, borrowed from one of [`Program::files`]
+    Code(&'static str),
+    Def(u32),
+    Undef(u32),
+    /// Evaluates a `#if` or `#elif` condition, jumps to `next` branch of the chain when false
+    Branch {
+        cond: u32,
+        next: u32,
+    },
+    /// End of a taken branch, jumps after the `#endif`
+    Jump(u32),
+    /// Errors found while compiling are only reported when reached, same as
+    /// [`PreProcessor::process`] does
+    Panic(Box<str>),
+}
+
+/// Files compiled by [`PreProcessor::compile`], includes are resolved and inlined, vars are
+/// interned to bits and conditions are turned into bitset tests, so processing is a single
+/// forward pass over the instructions that skips inactive blocks altogether
+///
+/// Gives the same output and panics as [`PreProcessor::process`], changes in the files after
+/// the program is compiled aren't visible
+pub struct Program {
+    insts: Vec<Inst>,
+    conds: Vec<Cond>,
+    vars: HashMap<SmartString<Compact>, u32>,
+    /// Keeps the lines of code referenced by [`Inst::Code`] alive
+    files: Vec<Rc<File>>,
+}
+
+impl Program {
+    /// Number of 64 bits words needed to hold every var
+    #[inline]
+    fn words(&self) -> usize {
+        self.vars.len().div_ceil(64)
+    }
+
+    /// Processes the program with `defines`, [`PreProcessor::defines`] aren't used
+    pub fn process(&self, defines: &DefineSet, mut f: impl FnMut(&str)) {
+        self.process_blocks(defines, |text| text.split('\n').for_each(&mut f));
+    }
+
+    /// Like [`Program::process`] but calls `f` once for a whole [`Line::Block`]
+    pub fn process_blocks(&self, defines: &DefineSet, mut f: impl FnMut(&str)) {
+        let mut bits = vec![0u64; self.words()];
+        for def in ["true", "1"]
+            .into_iter()
+            .chain(defines.iter().map(|def| def.as_str()))
+        {
+            if let Some(&var) = self.vars.get(def) {
+                set(&mut bits, var);
+            }
+        }
+
+        let mut pc = 0;
+        while let Some(inst) = self.insts.get(pc) {
+            pc += 1;
+            match inst {
+                Inst::Code(line) => (f)(line),
+                &Inst::Def(var) => set(&mut bits, var),
+                &Inst::Undef(var) => bits[var as usize / 64] &= !(1 << (var % 64)),
+                &Inst::Branch { cond, next } => {
+                    if !self.conds[cond as usize].eval(&bits) {
+                        pc = next as usize;
+                    }
+                }
+                &Inst::Jump(target) => pc = target as usize,
+                Inst::Panic(message) => panic!("{}", message),
+            }
+        }
+    }
+
+    pub fn process_to_str(&self, defines: &DefineSet, string: &mut String) {
+        self.process_blocks(defines, |text| {
+            string.push_str(text);
+            string.push('\n');
+        });
+    }
+}
+
+#[inline(always)]
+fn set(bits: &mut [u64], var: u32) {
+    bits[var as usize / 64] |= 1 << (var % 64);
+}
+
+#[inline(always)]
+fn get(bits: &[u64], var: u32) -> bool {
+    bits[var as usize / 64] & (1 << (var % 64)) != 0
+}
+
+impl Cond {
+    fn eval(&self, bits: &[u64]) -> bool {
+        match self {
+            Cond::All(masks) => masks.iter().all(|mask| {
+                let word = bits[mask.word as usize];
+                word & mask.set == mask.set && word & mask.clear == 0
+            }),
+            Cond::Any(masks) => masks.iter().any(|mask| {
+                let word = bits[mask.word as usize];
+                word & mask.set != 0 || !word & mask.clear != 0
+            }),
+            Cond::Ops(ops) => {
+                let mut stack: SmallVec<[bool; 16]> = SmallVec::new();
+                for op in ops {
+                    let value = match op {
+                        &VarOp::Var(var) => get(bits, var),
+                        VarOp::And | VarOp::Or => {
+                            let b = stack.pop().expect("malformed exp");
+                            let a = stack.pop().expect("malformed exp");
+                            if let VarOp::And = op {
+                                a && b
+                            } else {
+                                a || b
+                            }
+                        }
+                        VarOp::Not => !stack.pop().expect("malformed exp"),
+                    };
+                    stack.push(value);
+                }
+                if stack.len() != 1 {
+                    panic!("malformed exp");
+                }
+                stack[0]
+            }
+        }
+    }
+}
+
+/// Expression tree used to find out if a condition is a plain conjunction or disjunction
+enum Node {
+    Var(u32),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn new(ops: &[VarOp]) -> Option<Self> {
+        let mut stack = vec![];
+        for op in ops {
+            let node = match op {
+                &VarOp::Var(var) => Node::Var(var),
+                VarOp::Not => Node::Not(Box::new(stack.pop()?)),
+                VarOp::And | VarOp::Or => {
+                    let b = Box::new(stack.pop()?);
+                    let a = Box::new(stack.pop()?);
+                    if let VarOp::And = op {
+                        Node::And(a, b)
+                    } else {
+                        Node::Or(a, b)
+                    }
+                }
+            };
+            stack.push(node);
+        }
+        if stack.len() != 1 {
+            return None;
+        }
+        stack.pop()
+    }
+
+    /// Collects the vars of a tree made only of `&&` or only of `||`, with negations
+    /// pushed down to the vars, returns `false` when the operators are mixed
+    fn literals(&self, negated: bool, and: bool, masks: &mut Vec<Mask>) -> bool {
+        match self {
+            &Node::Var(var) => {
+                let word = var / 64;
+                let bit = 1 << (var % 64);
+                let index = match masks.iter().position(|mask| mask.word == word) {
+                    Some(index) => index,
+                    None => {
+                        masks.push(Mask {
+                            word,
+                            set: 0,
+                            clear: 0,
+                        });
+                        masks.len() - 1
+                    }
+                };
+                if negated {
+                    masks[index].clear |= bit;
+                } else {
+                    masks[index].set |= bit;
+                }
+                true
+            }
+            Node::Not(node) => node.literals(!negated, and, masks),
+            Node::And(a, b) | Node::Or(a, b) => {
+                // De Morgan, `!(a && b) == !a || !b`
+                let is_and = matches!(self, Node::And(..)) != negated;
+                is_and == and && a.literals(negated, and, masks) && b.literals(negated, and, masks)
+            }
+        }
+    }
+}
+
+/// Open `#if` chain while compiling
+struct Chain {
+    /// Last branch of the chain, its `next` jump is patched by the following branch
+    branch: Option<usize>,
+    /// Jumps from the end of each branch to after the `#endif`
+    jumps: Vec<usize>,
+    has_else: bool,
+}
+
+struct Compiler<'a> {
+    pre_processor: &'a mut PreProcessor,
+    program: Program,
+    include_stack: Vec<SmartString<Compact>>,
+}
+
+impl Compiler<'_> {
+    fn var(&mut self, name: &str) -> u32 {
+        let next = self.program.vars.len() as u32;
+        *self.program.vars.entry(name.into()).or_insert(next)
+    }
+
+    fn cond(&mut self, exp: &Exp) -> u32 {
+        let ops: Vec<VarOp> = exp
+            .ops
+            .iter()
+            .map(|op| match op {
+                Op::Var(var) => VarOp::Var(self.var(var)),
+                Op::And => VarOp::And,
+                Op::Or => VarOp::Or,
+                Op::Not => VarOp::Not,
+            })
+            .collect();
+
+        let mut cond = Cond::Ops(ops);
+        if let Cond::Ops(ops) = &cond {
+            if let Some(node) = Node::new(ops) {
+                let mut masks = vec![];
+                if node.literals(false, true, &mut masks) {
+                    cond = Cond::All(masks);
+                } else {
+                    masks.clear();
+                    if node.literals(false, false, &mut masks) {
+                        cond = Cond::Any(masks);
+                    }
+                }
+            }
+        }
+
+        self.program.conds.push(cond);
+        (self.program.conds.len() - 1) as u32
+    }
+
+    /// Points the `next` jump of `branch` to the current instruction
+    fn patch_branch(&mut self, branch: Option<usize>) {
+        let target = self.program.insts.len() as u32;
+        if let Some(Inst::Branch { next, .. }) =
+            branch.map(|branch| &mut self.program.insts[branch])
+        {
+            *next = target;
+        }
+    }
+
+    /// Points the pending jumps of `chain` to the current instruction
+    fn patch(&mut self, chain: &mut Chain) {
+        self.patch_branch(chain.branch.take());
+        let target = self.program.insts.len() as u32;
+        for jump in chain.jumps.drain(..) {
+            self.program.insts[jump] = Inst::Jump(target);
+        }
+    }
+
+    /// Structure errors stop the processing of a file even inside inactive blocks,
+    /// so every pending jump of the file leads to the panic
+    fn abort(&mut self, chains: &mut Vec<Chain>, message: String) {
+        for mut chain in chains.drain(..) {
+            self.patch(&mut chain);
+        }
+        self.program.insts.push(Inst::Panic(message.into()));
+    }
+
+    fn file(&mut self, file_path: &str, file: Rc<File>) {
+        self.program.files.push(file.clone());
+        // lines of code are kept alive by `Program::files`
+        let mut chains: Vec<Chain> = vec![];
+
+        for (line_number, line) in crate::numbered(&file.lines) {
+            let inst = match line {
+                Line::Code(line)
+                | Line::Block { code: line, .. }
+                | Line::Rem(line)
+                | Line::Unknown { line, .. } => Inst::Code(line),
+                Line::Inc(inc) => {
+                    if self.include_stack.iter().any(|path| path == inc) {
+                        Inst::Panic(
+                            format!(
+                                "recursive include of \"{}\" at {}:{}",
+                                inc, file_path, line_number
+                            )
+                            .into(),
+                        )
+                    } else if let Some(inc_file) = self.pre_processor.preload(inc) {
+                        self.include_stack.push((*inc).into());
+                        self.file(inc, inc_file);
+                        self.include_stack.pop();
+                        continue;
+                    } else {
+                        Inst::Panic(
+                            format!(
+                                "couldn't find include file \"{}\" at {}:{}",
+                                inc, file_path, line_number
+                            )
+                            .into(),
+                        )
+                    }
+                }
+                &Line::Def(def) => Inst::Def(self.var(def)),
+                &Line::Undef(def) => Inst::Undef(self.var(def)),
+                Line::If(exp) => {
+                    chains.push(Chain {
+                        branch: Some(self.program.insts.len()),
+                        jumps: vec![],
+                        has_else: false,
+                    });
+                    Inst::Branch {
+                        cond: self.cond(exp),
+                        next: u32::MAX,
+                    }
+                }
+                Line::Elif(_) | Line::Else => {
+                    let directive = if let Line::Else = line {
+                        "else"
+                    } else {
+                        "elif"
+                    };
+                    let Some(mut chain) = chains.pop() else {
+                        let message = format!(
+                            "`{}` doesn't have a maching `if` at {}:{}",
+                            directive, file_path, line_number
+                        );
+                        self.abort(&mut chains, message);
+                        return;
+                    };
+                    if chain.has_else {
+                        chains.push(chain);
+                        let message = format!(
+                            "`{}` after `else` at {}:{}",
+                            directive, file_path, line_number
+                        );
+                        self.abort(&mut chains, message);
+                        return;
+                    }
+
+                    // the previous branch was taken
+                    chain.jumps.push(self.program.insts.len());
+                    self.program.insts.push(Inst::Jump(u32::MAX));
+
+                    // the previous branch wasn't taken
+                    self.patch_branch(chain.branch.take());
+
+                    if let Line::Elif(exp) = line {
+                        chain.branch = Some(self.program.insts.len());
+                        let cond = self.cond(exp);
+                        self.program.insts.push(Inst::Branch {
+                            cond,
+                            next: u32::MAX,
+                        });
+                    } else {
+                        chain.has_else = true;
+                    }
+                    chains.push(chain);
+                    continue;
+                }
+                Line::Endif => {
+                    let Some(mut chain) = chains.pop() else {
+                        let message = format!(
+                            "`endif` doesn't have a maching `if` at {}:{}",
+                            file_path, line_number
+                        );
+                        self.abort(&mut chains, message);
+                        return;
+                    };
+                    self.patch(&mut chain);
+                    continue;
+                }
+            };
+            self.program.insts.push(inst);
+        }
+
+        if !chains.is_empty() {
+            let message = format!("some `if` block is open in file {}", file_path);
+            self.abort(&mut chains, message);
+        }
+    }
+}
+
+impl PreProcessor {
+    /// Compiles `path` and every file it can include into a [`Program`], useful when
+    /// processing the same file with thousands of define sets
+    pub fn compile(&mut self, path: &str) -> Program {
+        let Some(file) = self.preload(path) else {
+            panic!("file \"{}\" not found", path);
+        };
+
+        let mut compiler = Compiler {
+            pre_processor: self,
+            program: Program {
+                insts: vec![],
+                conds: vec![],
+                vars: HashMap::new(),
+                files: vec![],
+            },
+            include_stack: vec![path.into()],
+        };
+        compiler.file(path, file);
+        compiler.program
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::{DefaultFileLoader, MemoryFileLoader};
+
+    use super::*;
+
+    fn pre_processor(files: &[(&str, &str)]) -> PreProcessor {
+        let mut file_loader = MemoryFileLoader::default();
+        for &(path, data) in files {
+            file_loader.files.insert(path.into(), data.into());
+        }
+        PreProcessor::with_loader(file_loader)
+    }
+
+    /// Every subset of `toggles` gives the same output when compiled
+    fn assert_same_output(pre_processor: &mut PreProcessor, path: &str, toggles: &[&str]) {
+        let program = pre_processor.compile(path);
+        for i in 0..1u32 << toggles.len() {
+            let defines: DefineSet = toggles
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| i & (1 << bit) != 0)
+                .map(|(_, &def)| def.into())
+                .collect();
+
+            let mut expected = String::new();
+            pre_processor.defines = defines.clone();
+            pre_processor.process_to_str(path, &mut expected);
+
+            let mut output = String::new();
+            program.process_to_str(&defines, &mut output);
+            assert_eq!(output, expected, "with {:?}", defines);
+        }
+    }
+
+    #[test]
+    fn compiled_program() {
+        let mut pre_processor = pre_processor(&[
+            (
+                "main.c",
+                "#if A && !B\nab\n#elif B || !C\n#include \"inc.h\"\n#elif (A || B) && D\nabd\n\
+                #else\n#if D\n#define E\n#else\n#undef A\n#endif\n#endif\n\
+                #if !(A || E) // comment\nnot ae\n#else\nae\n#endif\n\
+                #if 1 && !0\n#version 450\n#endif\n#if false\n#include \"missing.h\"\n#endif",
+            ),
+            (
+                "inc.h",
+                "#if C\n#define A\n#elif D\n#undef D\n#endif\n#if !!A && !D\ninc a\n#endif",
+            ),
+        ]);
+        assert_same_output(&mut pre_processor, "main.c", &["A", "B", "C", "D", "E"]);
+    }
+
+    #[test]
+    fn compiled_bevy() {
+        let mut pre_processor = PreProcessor {
+            file_loader: Box::new({
+                let mut file_loader = DefaultFileLoader::default();
+                file_loader.search_paths.push("benches/files/bevy".into());
+                file_loader
+            }),
+            ..Default::default()
+        };
+        assert_same_output(
+            &mut pre_processor,
+            "pbr/pbr.wgsl",
+            &[
+                "VERTEX_UVS",
+                "VERTEX_TANGENTS",
+                "STANDARDMATERIAL_NORMAL_MAP",
+                "SKINNED",
+                "TONEMAP_IN_SHADER",
+                "DEBAND_DITHER",
+            ],
+        );
+    }
+
+    #[test]
+    fn compiled_panics() {
+        for (data, defines) in [
+            ("#if 0\n#else\n#else\n#endif", &[][..]),
+            ("#if A\n#include \"missing.h\"\n#endif", &["A"]),
+            ("#if A\n#include \"main.c\"\n#endif", &["A"]),
+            ("a\n#elif 1", &[]),
+            ("#if 1\n#endif\n#endif", &[]),
+            ("#if 0\n#if 1", &[]),
+            ("#include \"open.h\"\n#endif", &[]),
+            ("#if 1\n#include \"close.h\"\n#if 1", &[]),
+        ] {
+            let mut pre_processor =
+                pre_processor(&[("main.c", data), ("open.h", "#if 1"), ("close.h", "#endif")]);
+            let defines: DefineSet = defines.iter().map(|&def| def.into()).collect();
+            let program = pre_processor.compile("main.c");
+
+            pre_processor.defines = defines.clone();
+            let expected = catch_unwind(AssertUnwindSafe(|| {
+                pre_processor.process("main.c", |_| {});
+            }))
+            .expect_err("should panic");
+            let err = catch_unwind(AssertUnwindSafe(|| program.process(&defines, |_| {})))
+                .expect_err("should panic");
+
+            let message = |err: Box<dyn core::any::Any + Send>| {
+                err.downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| err.downcast_ref::<&str>().map(|s| String::from(*s)))
+                    .unwrap()
+            };
+            assert_eq!(message(err), message(expected), "in {:?}", data);
+        }
+
+        // malformed files that are never reached don't panic
+        let mut pre_processor = pre_processor(&[
+            ("main.c", "#if 0\n#include \"open.h\"\n#endif"),
+            ("open.h", "#if 1"),
+        ]);
+        assert_same_output(&mut pre_processor, "main.c", &[]);
+    }
+}
@@ -0,0 +1,802 @@
+//! Portable implementation of the parsers, used as the reference to diff the SIMD backends
+//! against and as the fallback in targets without SSE2
+//!
+//! Every function here must produce exactly the same [`Line`]s and [`Exp`]s as it's SIMD
+//! counterpart, quirks included
+
+use alloc::vec::Vec;
+
+use beef::Cow;
+use smallvec::SmallVec;
+
+use crate::{
+    exp::{self, Exp, Op},
+    Config, Directive, Line, Position,
+};
+
+#[inline(always)]
+fn line(data: &str, start: usize, mut end: usize) -> &str {
+    // remove '\r' if any
+    if start < end && data.as_bytes()[end - 1] == b'\r' {
+        end -= 1;
+    }
+    &data[start..end]
+}
+
+/// Builds the same bit mask the SIMD backends get from comparing `ch` against a table of 16 chars
+#[inline(always)]
+fn break_mask(ch: u8, table: &[u8; 16]) -> i32 {
+    let mut mask = 0;
+    for (i, &other) in table.iter().enumerate() {
+        if ch == other {
+            mask |= 1 << i;
+        }
+    }
+    mask
+}
+
+#[derive(PartialOrd, Ord, PartialEq, Eq, Clone, Copy)]
+enum Token {
+    And = 0,
+    Or = 1,
+    Not = 2,
+    Noop,
+    LParen,
+}
+
+// translate a [`Token`] to a `Op` and precedence
+const OPERATORS: &[Op<'static>] = &[Op::And, Op::Or, Op::Not];
+const PRECEDENCE: &[usize] = &[0, 0, 1];
+
+/// Shunting yard algorithm shared by [`parse_exp`] and the directive parser,
+/// returns the expression and the offset where it stopped
+///
+/// When `config` is some the expression is part of a directive, it will stop at the end
+/// of the line or at the begining of a comment
+fn exp<'a>(
+    data: &'a str,
+    mut offset: usize,
+    config: Option<&Config>,
+) -> Result<(Exp<'a>, usize), exp::Error> {
+    let bytes = data.as_bytes();
+
+    let mut stack: SmallVec<[(Token, usize); 16]> = SmallVec::new();
+    let mut ops = Vec::with_capacity(16);
+
+    let directive = config.is_some();
+    let comment = config.map(|config| config.comment.as_bytes());
+    let comment = comment.unwrap_or_default();
+    let comment_char = comment.first().copied().unwrap_or_default();
+    let comment_rem = comment.get(1..).unwrap_or_default();
+
+    // '\n' is used when multi-line comments are disabled since it already breaks the expression
+    let comment_begin = config.map(|config| config.comment_begin.as_bytes());
+    let comment_begin = comment_begin.unwrap_or_default();
+    let comment_begin_char = comment_begin.first().copied().unwrap_or(b'\n');
+    let comment_begin_rem = comment_begin.get(1..).unwrap_or_default();
+
+    // string literals aren't allowed inside expressions, '\n' is used to fill the unused slots
+    let delimiters = config.map(|config| config.string_delimiters.as_bytes());
+    let delimiters = delimiters.unwrap_or_default();
+    let delimiter = |i: usize| delimiters.get(i).copied().unwrap_or(b'\n');
+
+    let (table, skip_mask) = if directive {
+        (
+            [
+                b'|',
+                b')',
+                b'(',
+                b'&',
+                b'!',
+                b' ',
+                b'\t',
+                b'\n',
+                b'\r',
+                b'\0',
+                comment_char,
+                comment_begin_char,
+                delimiter(0),
+                delimiter(1),
+                delimiter(2),
+                delimiter(3),
+            ],
+            0b0000_0011_0110_0000,
+        )
+    } else {
+        (
+            [
+                b'|', b')', b'(', b'&', b'!', b' ', b'\t', b'\n', b'\r', b'\0', 0, 0, 0, 0, 0, 0,
+            ],
+            0b1111_1111_1110_0000,
+        )
+    };
+
+    let is_var_break = |ch: u8| match ch {
+        b' ' | b'\t' | b'!' | b'&' | b'(' | b')' | b'|' => true,
+        b'\r' | b'\n' => directive,
+        _ => {
+            directive
+                && (ch == comment_char || ch == comment_begin_char || delimiters.contains(&ch))
+        }
+    };
+
+    let mut token_offset = offset;
+
+    // used to validate the expression, operands and binary operators must alternate
+    let mut expect_operand = true;
+
+    loop {
+        if offset >= bytes.len() {
+            break;
+        }
+
+        let ch = bytes[offset];
+        offset += 1;
+
+        let break_mask = break_mask(ch, &table);
+
+        if break_mask != 0 {
+            if break_mask & skip_mask != 0 {
+                // accept and skip
+                token_offset = offset;
+                continue;
+            }
+
+            if directive && break_mask & 0b1000_0000 != 0 {
+                // enter, roll back and break
+                offset -= 1;
+                break;
+            }
+
+            if break_mask & 0b1111_0000_0000_0000 != 0 {
+                return Err(exp::Error {
+                    offset: offset - 1,
+                    len: 1,
+                    message: Cow::borrowed("unexpected string literal"),
+                });
+            }
+
+            if break_mask & 0b0000_0100_0000_0000 != 0 && bytes[offset..].starts_with(comment_rem) {
+                // roll back and break
+                offset -= 1;
+                break;
+            }
+
+            if break_mask & 0b0000_1000_0000_0000 != 0
+                && bytes[offset..].starts_with(comment_begin_rem)
+            {
+                // multi-line comment, roll back and break
+                offset -= 1;
+                break;
+            }
+
+            if break_mask & 0b0000_0100 != 0 {
+                if !expect_operand {
+                    return Err(exp::Error {
+                        offset: offset - 1,
+                        len: 1,
+                        message: Cow::borrowed("expecting operator"),
+                    });
+                }
+                token_offset = offset; // accept the token
+                stack.push((Token::LParen, offset));
+                continue;
+            }
+
+            if break_mask & 0b0000_0010 != 0 {
+                if expect_operand {
+                    return Err(exp::Error {
+                        offset: offset - 1,
+                        len: 1,
+                        message: Cow::borrowed("expecting operand"),
+                    });
+                }
+                token_offset = offset; // accept the token
+                loop {
+                    if let Some((token, _)) = stack.pop() {
+                        if token != Token::LParen {
+                            ops.push(OPERATORS[token as usize]);
+                        } else {
+                            break;
+                        }
+                    } else {
+                        return Err(exp::Error {
+                            offset: offset - 1,
+                            len: 1,
+                            message: Cow::borrowed("unmached `)`"),
+                        });
+                    }
+                }
+                continue;
+            }
+
+            let op0;
+            if break_mask & 0b0000_1000 != 0 {
+                // and
+                if offset >= bytes.len() || bytes[offset] != b'&' {
+                    return Err(exp::Error {
+                        offset: offset - 1,
+                        len: 1,
+                        message: Cow::borrowed("expecting `&&`"),
+                    });
+                }
+                offset += 1;
+                op0 = Token::And;
+            } else if break_mask & 0b0000_0001 != 0 {
+                // or
+                if offset >= bytes.len() || bytes[offset] != b'|' {
+                    return Err(exp::Error {
+                        offset: offset - 1,
+                        len: 1,
+                        message: Cow::borrowed("expecting `||`"),
+                    });
+                }
+                offset += 1;
+                op0 = Token::Or;
+            } else if break_mask & 0b0001_0000 != 0 {
+                // not
+                op0 = Token::Not;
+            } else {
+                op0 = Token::Noop;
+            }
+            if op0 != Token::Noop {
+                if op0 != Token::Not {
+                    // binary operator
+                    if expect_operand {
+                        return Err(exp::Error {
+                            offset: offset - 2,
+                            len: 2,
+                            message: Cow::borrowed("expecting operand"),
+                        });
+                    }
+                    expect_operand = true;
+                }
+                token_offset = offset; // accept the token
+                loop {
+                    let pre0 = PRECEDENCE[op0 as usize];
+                    if let Some(&(op1, _)) = stack.last() {
+                        // a prefix `!` is right associative and must not pop the operators before it
+                        if op1 == Token::LParen || (op0 == Token::Not && expect_operand) {
+                            break;
+                        }
+                        let pre1 = PRECEDENCE[op1 as usize];
+                        if pre0 <= pre1 {
+                            ops.push(OPERATORS[op1 as usize]);
+                            stack.pop();
+                            continue;
+                        }
+                    }
+                    break;
+                }
+                stack.push((op0, offset));
+                continue;
+            }
+        }
+
+        // variable
+        while offset < bytes.len() && !is_var_break(bytes[offset]) {
+            offset += 1;
+        }
+
+        if !expect_operand {
+            return Err(exp::Error {
+                offset: token_offset,
+                len: offset - token_offset,
+                message: Cow::borrowed("expecting operator"),
+            });
+        }
+        expect_operand = false;
+
+        ops.push(Op::Var(&data[token_offset..offset]));
+
+        token_offset = offset; // accept the token
+    }
+
+    while let Some((token, token_offset)) = stack.pop() {
+        if token == Token::LParen {
+            return Err(exp::Error {
+                offset: token_offset - 1,
+                len: 1,
+                message: Cow::borrowed("unmached `(`"),
+            });
+        }
+        ops.push(OPERATORS[token as usize]);
+    }
+
+    if expect_operand {
+        return Err(exp::Error {
+            offset,
+            len: 0,
+            message: Cow::borrowed("expecting operand"),
+        });
+    }
+
+    Ok((Exp { ops }, offset))
+}
+
+struct Parser<'a, 'p> {
+    data: &'a str,
+    /// Path of the file reported in the errors, empty when it isn't known
+    path: &'p str,
+    ptr: usize,
+    line_count: usize,
+    line_ptr: usize,
+    /// How many multi-line comments are open
+    comment_depth: usize,
+}
+
+impl<'a, 'p> Parser<'a, 'p> {
+    #[inline(always)]
+    fn bytes(&self) -> &'a [u8] {
+        self.data.as_bytes()
+    }
+
+    fn ignore_space(&mut self) -> bool {
+        let bytes = self.bytes();
+        while self.ptr < bytes.len() && matches!(bytes[self.ptr], b' ' | b'\t') {
+            self.ptr += 1;
+        }
+        self.ptr < bytes.len()
+    }
+
+    fn find(&mut self, ch: u8) -> bool {
+        let bytes = self.bytes();
+        while self.ptr < bytes.len() && bytes[self.ptr] != ch {
+            self.ptr += 1;
+        }
+        self.ptr < bytes.len()
+    }
+
+    fn find_space_or_enter(&mut self) -> bool {
+        let bytes = self.bytes();
+        while self.ptr < bytes.len() && !matches!(bytes[self.ptr], b' ' | b'\t' | b'\n' | b'\r') {
+            self.ptr += 1;
+        }
+        self.ptr < bytes.len()
+    }
+
+    /// Moves to the end of the line keeping track of the multi-line comments
+    fn skip_line(&mut self, config: &Config) {
+        let bytes = self.bytes();
+        let begin = config.comment_begin.as_bytes();
+        let end = config.comment_end.as_bytes();
+        let comment = config.comment.as_bytes();
+
+        while self.ptr < bytes.len() && bytes[self.ptr] != b'\n' {
+            let rest = &bytes[self.ptr..];
+            if begin.is_empty() {
+                // multi-line comments are disabled
+            } else if self.comment_depth == 0 {
+                if rest.starts_with(begin) {
+                    self.comment_depth = 1;
+                    self.ptr += begin.len();
+                    continue;
+                }
+                if !comment.is_empty() && rest.starts_with(comment) {
+                    // single line comment, nothing else to look for
+                    self.find(b'\n');
+                    return;
+                }
+                if config.string_delimiters.as_bytes().contains(&rest[0]) {
+                    // comments inside string literals are ignored
+                    self.skip_literal(rest[0], config);
+                    continue;
+                }
+            } else {
+                if rest.starts_with(end) {
+                    self.comment_depth -= 1;
+                    self.ptr += end.len();
+                    continue;
+                }
+                if config.nested_comments && rest.starts_with(begin) {
+                    self.comment_depth += 1;
+                    self.ptr += begin.len();
+                    continue;
+                }
+            }
+
+            self.ptr += 1;
+        }
+    }
+
+    /// Skips a string literal starting at `self.ptr`, stops at the closing `delimiter`
+    /// or at the end of the line
+    fn skip_literal(&mut self, delimiter: u8, config: &Config) {
+        let bytes = self.bytes();
+
+        // consume the opening delimiter
+        self.ptr += 1;
+
+        while self.ptr < bytes.len() && bytes[self.ptr] != b'\n' {
+            let ch = bytes[self.ptr];
+            self.ptr += 1;
+            if ch == delimiter {
+                return;
+            }
+
+            // escaped char, unless it's the end of the line
+            if Some(ch) == config.string_escape
+                && self.ptr < bytes.len()
+                && bytes[self.ptr] != b'\n'
+            {
+                self.ptr += 1;
+            }
+        }
+    }
+
+    /// Checks if `ptr` is at a word boundary, that is a white space, the begining of a comment,
+    /// a `(` or the end of the line, after the keyword of `directive` the char that can start
+    /// its argument is also accepted, like in `#if!A` or `#include"a"`
+    fn is_boundary(&self, ptr: usize, directive: Option<Directive>, config: &Config) -> bool {
+        let rest = &self.bytes()[ptr..];
+        match (rest.first(), directive) {
+            (None | Some(b' ' | b'\t' | b'\r' | b'\n' | b'('), _) => true,
+            (Some(b'!'), Some(Directive::If | Directive::Elif)) => true,
+            (Some(&ch), Some(Directive::Include)) if ch == config.include_begin => true,
+            _ => {
+                let comment = config.comment.as_bytes();
+                let comment_begin = config.comment_begin.as_bytes();
+                (!comment.is_empty() && rest.starts_with(comment))
+                    || (!comment_begin.is_empty() && rest.starts_with(comment_begin))
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn enter(&mut self) {
+        self.line_count += 1;
+        self.line_ptr = self.ptr;
+    }
+
+    /// Position of `ptr` reported in the errors
+    fn position(&self, ptr: usize) -> Position<'p> {
+        Position {
+            path: self.path,
+            line: self.line_count,
+            column: self.data[self.line_ptr..ptr].chars().count(),
+        }
+    }
+
+    fn exp(&mut self, config: &Config) -> Exp<'a> {
+        match exp(self.data, self.ptr, Some(config)) {
+            Ok((exp, offset)) => {
+                self.ptr = offset;
+                exp
+            }
+            Err(err) => panic!("{} {}", err.message, self.position(err.offset)),
+        }
+    }
+
+    fn name(&mut self, config: &Config) -> &'a str {
+        self.ignore_space();
+
+        let def_ptr = self.ptr;
+
+        if self.bytes()[self.ptr..].starts_with(config.comment.as_bytes()) {
+            panic!(
+                "missing define name of `define` {}",
+                self.position(self.ptr)
+            );
+        }
+
+        if !self.find_space_or_enter() {
+            self.ptr = self.bytes().len();
+        }
+
+        &self.data[def_ptr..self.ptr]
+    }
+
+    fn parse(&mut self, config: &Config, mut f: impl FnMut(Line<'a>)) {
+        let data = self.data;
+        let bytes = self.bytes();
+        let end = bytes.len();
+
+        while self.ptr < end {
+            if !self.ignore_space() {
+                // nothing left but a line made of white spaces
+                if self.line_ptr < end {
+                    (f)(Line::Code(&data[self.line_ptr..self.line_ptr]));
+                }
+                break;
+            }
+
+            let ch = bytes[self.ptr];
+
+            if ch == b'\n' {
+                // empty line, notice that the line pointer is inportant
+                (f)(Line::Code(&data[self.line_ptr..self.line_ptr]));
+
+                // consume '\n'
+                self.ptr += 1;
+
+                self.enter();
+
+                continue;
+            }
+
+            if ch == config.special_char && self.comment_depth == 0 {
+                // directive, unless inside a multi-line comment
+                self.ptr += 1;
+
+                if self.ptr < end {
+                    let rest = &bytes[self.ptr..];
+
+                    // keywords must be followed by a word boundary, so `#ifdef` isn't taken as `#if`,
+                    // in case of many matches the longest keyword wins
+                    let mut directive = None;
+                    let mut keyword_len = 0;
+                    for (keyword, kind) in &config.directives {
+                        let keyword = keyword.as_bytes();
+                        if keyword.len() > keyword_len
+                            && rest.starts_with(keyword)
+                            && self.is_boundary(self.ptr + keyword.len(), Some(*kind), config)
+                        {
+                            directive = Some(*kind);
+                            keyword_len = keyword.len();
+                        }
+                    }
+                    self.ptr += keyword_len;
+
+                    match directive {
+                        Some(Directive::If) => (f)(Line::If(self.exp(config))),
+                        Some(Directive::Elif) => (f)(Line::Elif(self.exp(config))),
+                        Some(Directive::Else) => (f)(Line::Else),
+                        Some(Directive::Endif) => (f)(Line::Endif),
+                        Some(Directive::Undef) => (f)(Line::Undef(self.name(config))),
+                        Some(Directive::Define) => (f)(Line::Def(self.name(config))),
+                        Some(Directive::Include) => {
+                            self.ignore_space();
+
+                            if self.ptr >= end || bytes[self.ptr] != config.include_begin {
+                                panic!(
+                                    "missing start delimiter '{:?}' of `include` {}",
+                                    char::from(config.include_begin),
+                                    self.position(self.ptr)
+                                );
+                            }
+
+                            // consume delimiter
+                            self.ptr += 1;
+
+                            let inc_ptr = self.ptr;
+
+                            if !self.find(config.include_end) {
+                                panic!(
+                                    "missing end delimiter '{:?}' of `include` {}",
+                                    char::from(config.include_end),
+                                    self.position(self.ptr)
+                                );
+                            }
+
+                            (f)(Line::Inc(line(data, inc_ptr, self.ptr)));
+
+                            // consume delimiter
+                            self.ptr += 1;
+                        }
+                        None => {
+                            // unknown directive, names are made of ASCII alphanumeric chars and `_`
+                            let name_ptr = self.ptr;
+                            while self.ptr < end
+                                && (bytes[self.ptr].is_ascii_alphanumeric()
+                                    || bytes[self.ptr] == b'_')
+                            {
+                                self.ptr += 1;
+                            }
+
+                            if self.ptr == name_ptr || !self.is_boundary(self.ptr, None, config) {
+                                // not a directive at all like `#[derive(Debug)]`, treated as a line of code
+                                self.skip_line(config);
+
+                                (f)(Line::Code(line(data, self.line_ptr, self.ptr)));
+                            } else {
+                                let name = &data[name_ptr..self.ptr];
+
+                                self.ignore_space();
+                                let rest_ptr = self.ptr;
+
+                                self.skip_line(config);
+
+                                (f)(Line::Unknown {
+                                    line: line(data, self.line_ptr, self.ptr),
+                                    name,
+                                    rest: line(data, rest_ptr, self.ptr),
+                                });
+                            }
+
+                            // skip '\n'
+                            self.ptr += 1;
+
+                            self.enter();
+
+                            continue;
+                        }
+                    }
+                } else {
+                    // a lone special char at the end, a line of code like when a line ending follows it
+                    (f)(Line::Code(line(data, self.line_ptr, self.ptr)));
+                    break;
+                }
+
+                if self.ptr >= end {
+                    break;
+                }
+
+                // account for "\r\n" line end format
+                if bytes[self.ptr] == b'\r' {
+                    self.ptr += 1;
+                    if self.ptr >= end {
+                        break;
+                    }
+                }
+
+                if bytes[self.ptr] != b'\n' {
+                    // remaning of the line if any will be treaded as a remaning of a line of code
+                    let rem_ptr = self.ptr;
+
+                    self.skip_line(config);
+
+                    (f)(Line::Rem(line(data, rem_ptr, self.ptr)));
+                }
+
+                // consume '\n'
+                self.ptr += 1;
+
+                self.enter();
+
+                continue;
+            }
+
+            self.skip_line(config);
+
+            (f)(Line::Code(line(data, self.line_ptr, self.ptr)));
+
+            // skip '\n'
+            self.ptr += 1;
+
+            self.enter();
+        }
+    }
+}
+
+pub fn parse_file<'a>(input: &'a str, config: &Config, f: impl FnMut(Line<'a>)) {
+    parse_file_at(input, "", config, f);
+}
+
+/// Like [`parse_file`] but the errors are reported with `path`
+pub(crate) fn parse_file_at<'a>(
+    input: &'a str,
+    path: &str,
+    config: &Config,
+    f: impl FnMut(Line<'a>),
+) {
+    // same as the SSE2 parser, the comment lengths aren't limited here
+    assert!(
+        config.comment_begin.is_empty() || !config.comment_end.is_empty(),
+        "`comment_end` can't be empty when `comment_begin` is \"{}\"",
+        config.comment_begin
+    );
+
+    let mut parser = Parser {
+        data: input,
+        path,
+        ptr: 0,
+        line_count: 1,
+        line_ptr: 0,
+        comment_depth: 0,
+    };
+
+    parser.parse(config, f);
+}
+
+pub fn parse_exp(exp: &str) -> Result<Exp<'_>, exp::Error> {
+    self::exp(exp, 0, None).map(|(exp, _)| exp)
+}
+
+#[cfg(all(test, any(target_arch = "x86", target_arch = "x86_64")))]
+mod tests {
+    use super::*;
+
+    fn lines<'a>(
+        data: &'a str,
+        parse: fn(&'a str, &Config, &mut dyn FnMut(Line<'a>)),
+    ) -> Vec<Line<'a>> {
+        let config = Config::default();
+        let mut lines = vec![];
+        parse(data, &config, &mut |line| lines.push(line));
+        lines
+    }
+
+    fn diff(data: &str) {
+        assert_eq!(
+            lines(data, |data, config, f| crate::sse2::parse_file(
+                data, config, f
+            )),
+            lines(data, |data, config, f| parse_file(data, config, f)),
+            "{:?}",
+            data
+        );
+    }
+
+    #[test]
+    fn same_as_sse2() {
+        for path in [
+            "benches/files/Native.g.cs",
+            "benches/files/shader.wgsl",
+            "benches/files/main.c",
+            "benches/files/main_inc.h",
+            "benches/files/bevy/pbr/pbr.wgsl",
+            "benches/files/bevy/pbr/pbr_functions.wgsl",
+            "benches/files/bevy/pbr/pbr_prepass.wgsl",
+        ] {
+            let data = std::fs::read_to_string(path).expect("file not found");
+            diff(&data);
+            diff(&data.replace('\n', "\r\n"));
+        }
+
+        // edge cases
+        diff("");
+        diff("#");
+        diff("a\n#");
+        diff("a\r\n  #");
+        diff("  ");
+        diff("#if A");
+        diff("#else");
+        diff("#endif\r");
+        diff("#else // comment");
+        diff("#define A\r\n#undef A");
+        diff("#include \"a\" rem\r\n");
+        diff("#if (A) // comment\n");
+        diff("#if A//comment");
+        diff("#pragma once\ncode\n  \n\t#if (A || !B) && C!\n#endif");
+        diff("/*#if A\n#endif*/\n#if B /* note\n#else */\n// /*\n#endif");
+        diff("#if A/**/\n/* /* */\n#pragma /*\n#endif\n*/");
+        diff("#ifdef A\n#if(B)\n#else_x\n#endif//\n #pragma once /*\n*/#version\n#[x]\n#\n#if!A");
+        diff("#include\"a\"\n#if!A\n#elif !B\n#elif!(C)\n#else!\n#endif!\n#include_x\"a\"");
+        diff("x = \"//\"; /* '\\'' \"\\\n#if A\n*/\n#define B \"/*\" '*/'\n\"\\\\\" /* \"\n#endif");
+    }
+
+    #[test]
+    fn same_exp_as_sse2() {
+        for exp in [
+            "a",
+            " ! a ",
+            "b && a !",
+            "(b && a)!",
+            "b||a",
+            "a\nb && c\r",
+            "(!a && b) || (!c && d && e) && (f || (!h && !c) || !(a && b))",
+            "!EDITOR && (IOS || ANDROID)",
+            "猴 && !小狗 || 房子",
+        ] {
+            assert_eq!(
+                crate::sse2::parse_exp(exp).ok(),
+                parse_exp(exp).ok(),
+                "{:?}",
+                exp
+            );
+        }
+
+        for exp in [
+            "",
+            "b && a !c ||",
+            "||a",
+            "b & a",
+            "((b&&a)",
+            "((b&&a)))",
+            "()",
+        ] {
+            assert!(crate::sse2::parse_exp(exp).is_err(), "{:?}", exp);
+            assert!(parse_exp(exp).is_err(), "{:?}", exp);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "`comment_end` can't be empty")]
+    fn empty_comment_end() {
+        let config = Config {
+            comment_end: "".into(),
+            ..Default::default()
+        };
+        parse_file("code", &config, |_| {});
+    }
+}
@@ -10,7 +10,7 @@ use smallvec::SmallVec;
 
 use crate::{
     exp::{self, Exp, Op},
-    str_from_range, str_from_raw_parts, Config, Line,
+    str_from_range, str_from_raw_parts, Config, Directive, Line, Position,
 };
 
 const MASK: [i32; 17] = {
@@ -20,7 +20,7 @@ const MASK: [i32; 17] = {
         if index >= arr.len() {
             break;
         }
-        arr[index] = ((1 << index) - 1) as i32;
+        arr[index] = (1 << index) - 1;
         index += 1;
     }
     arr
@@ -30,46 +30,152 @@ const MASK: [i32; 17] = {
 unsafe fn line<'a>(ptr: *const u8, mut ptr_end: *const u8) -> &'a str {
     // todo: bake inside the Parser::enter fn
     // remove '\r' if any
-    let prev = ptr_end.sub(1);
-    if ptr <= prev && *prev == b'\r' {
-        ptr_end = prev;
+    if ptr < ptr_end && *ptr_end.sub(1) == b'\r' {
+        ptr_end = ptr_end.sub(1);
     }
     str_from_raw_parts(ptr, ptr_end.offset_from(ptr) as usize)
 }
 
-// safety: `alen` and `b.len()` must be up to 16 characters long
+/// Loads 16 bytes starting at `ptr` without reading past `ptr_end`, missing bytes are zeroed
 #[inline(always)]
-unsafe fn start_with(a: __m128i, alen: usize, b: &[u8]) -> bool {
-    if alen < b.len() {
+unsafe fn load(ptr: *const u8, ptr_end: *const u8) -> __m128i {
+    if ptr_end.offset_from(ptr) >= 16 {
+        return _mm_loadu_si128(ptr as *const _); // 6 cycles
+    }
+
+    // near the end of the data, copy into a padded buffer to avoid out of bounds reads
+    let mut buffer = [0u8; 16];
+    if ptr < ptr_end {
+        core::ptr::copy_nonoverlapping(ptr, buffer.as_mut_ptr(), ptr_end.offset_from(ptr) as usize);
+    }
+    _mm_loadu_si128(buffer.as_ptr() as *const _)
+}
+
+/// Up to 16 chars loaded once, so matching them doesn't copy them every time
+#[derive(Clone, Copy)]
+struct Pattern {
+    chunk: __m128i,
+    len: usize,
+}
+
+impl Pattern {
+    // safety: `bytes` must be up to 16 characters long
+    #[inline(always)]
+    unsafe fn new(bytes: &[u8]) -> Self {
+        Self {
+            chunk: load(bytes.as_ptr(), bytes.as_ptr().add(bytes.len())),
+            len: bytes.len(),
+        }
+    }
+}
+
+/// The strings of a [`Config`] matched with [`start_with`]
+struct Patterns {
+    /// Each keyword along with the directive it stands for
+    keywords: SmallVec<[(Pattern, Directive); 8]>,
+    comment: Pattern,
+    comment_begin: Pattern,
+    comment_end: Pattern,
+    /// `comment` and `comment_begin` without their first char
+    comment_rem: Pattern,
+    comment_begin_rem: Pattern,
+}
+
+impl Patterns {
+    // safety: the lengths of the strings must be already checked
+    unsafe fn new(config: &Config) -> Self {
+        let comment = config.comment.as_bytes();
+        let comment_begin = config.comment_begin.as_bytes();
+        Self {
+            keywords: config
+                .directives
+                .iter()
+                .map(|(keyword, kind)| (Pattern::new(keyword.as_bytes()), *kind))
+                .collect(),
+            comment: Pattern::new(comment),
+            comment_begin: Pattern::new(comment_begin),
+            comment_end: Pattern::new(config.comment_end.as_bytes()),
+            comment_rem: Pattern::new(comment.get(1..).unwrap_or_default()),
+            comment_begin_rem: Pattern::new(comment_begin.get(1..).unwrap_or_default()),
+        }
+    }
+}
+
+#[inline(always)]
+unsafe fn start_with(a: __m128i, alen: usize, b: Pattern) -> bool {
+    if alen < b.len {
         // not enough characters
         return false;
     }
 
-    let cmp_mask = _mm_movemask_epi8(_mm_cmpeq_epi8(a, _mm_loadu_si128(b.as_ptr() as *const _))); // 6 + 1 + 3  cycles
-    return (cmp_mask & MASK[b.len()]) == MASK[b.len()];
+    let cmp_mask = _mm_movemask_epi8(_mm_cmpeq_epi8(a, b.chunk)); // 1 + 3  cycles
+    (cmp_mask & MASK[b.len]) == MASK[b.len]
 }
 
-struct Parser {
+struct Parser<'p> {
+    /// Path of the file reported in the errors, empty when it isn't known
+    path: &'p str,
     ptr: *const u8,
     ptr_end: *const u8,
     line_count: usize,
     line_ptr: *const u8,
+    /// How many multi-line comments are open
+    comment_depth: usize,
+    patterns: Patterns,
 }
 
-impl Parser {
-    fn new() -> Self {
+impl<'p> Parser<'p> {
+    unsafe fn new(path: &'p str, config: &Config) -> Self {
+        // make some assertions about the lenght of the comments
+        assert!(
+            config.comment.len() <= 16,
+            "`comment` \"{}\" exceeded 16 chars limit",
+            config.comment
+        );
+        assert!(
+            config.comment_begin.len() <= 16,
+            "`comment_begin` \"{}\" exceeded 16 chars limit",
+            config.comment_begin
+        );
+        assert!(
+            config.comment_end.len() <= 16,
+            "`comment_end` \"{}\" exceeded 16 chars limit",
+            config.comment_end
+        );
+        assert!(
+            config.comment_begin.is_empty() || !config.comment_end.is_empty(),
+            "`comment_end` can't be empty when `comment_begin` is \"{}\"",
+            config.comment_begin
+        );
+        assert!(
+            config.string_delimiters.len() <= 4 && config.string_delimiters.is_ascii(),
+            "`string_delimiters` \"{}\" must be up to 4 ASCII chars",
+            config.string_delimiters
+        );
+        for (keyword, _) in &config.directives {
+            assert!(
+                !keyword.is_empty() && keyword.len() <= 16,
+                "directive keyword \"{}\" must be between 1 and 16 chars long",
+                keyword
+            );
+        }
+
         Self {
+            path,
             ptr: null(),
             ptr_end: null(),
             line_count: 0,
             line_ptr: null(),
+            comment_depth: 0,
+            // loaded once instead of on every match
+            patterns: Patterns::new(config),
         }
     }
 
     #[inline(always)]
     unsafe fn mask_and_find(&mut self, f: impl Fn(__m128i) -> i32) -> bool {
         while self.ptr < self.ptr_end {
-            let chunk = _mm_loadu_si128(self.ptr as *const _); // 6 cycles
+            let chunk = load(self.ptr, self.ptr_end); // 6 cycles
             let mask = (f)(chunk); // 8 cycles
             if mask != 0 {
                 // found something
@@ -77,6 +183,10 @@ impl Parser {
 
                 // out of bounds check
                 self.ptr = self.ptr.add(offset);
+                if self.ptr >= self.ptr_end {
+                    self.ptr = self.ptr_end;
+                    return false;
+                }
 
                 return true;
             } else {
@@ -84,15 +194,17 @@ impl Parser {
             }
         }
 
+        self.ptr = self.ptr_end;
         false
     }
 
     unsafe fn ignore_space(&mut self) -> bool {
         self.mask_and_find(|chunk| {
+            // only the lower 16 bits are valid
             !_mm_movemask_epi8(_mm_or_si128(
                 _mm_cmpeq_epi8(chunk, _mm_set1_epi8(b' ' as i8)), // 0x20 (32)
                 _mm_cmpeq_epi8(chunk, _mm_set1_epi8(b'\t' as i8)), // 0x0B (11)
-            )) // 8 cycles
+            )) & 0xFFFF // 9 cycles
         })
     }
 
@@ -109,11 +221,150 @@ impl Parser {
                     _mm_cmpeq_epi8(chunk, _mm_set1_epi8(b' ' as i8)), // 0x20 (32)
                     _mm_cmpeq_epi8(chunk, _mm_set1_epi8(b'\t' as i8)), // 0x0B (11)
                 ),
-                _mm_cmpeq_epi8(chunk, _mm_set1_epi8(b'\n' as i8)), // 0x0A (10)
-            )) // 10 cycles
+                _mm_or_si128(
+                    _mm_cmpeq_epi8(chunk, _mm_set1_epi8(b'\n' as i8)), // 0x0A (10)
+                    _mm_cmpeq_epi8(chunk, _mm_set1_epi8(b'\r' as i8)), // 0x0D (13)
+                ),
+            )) // 12 cycles
         })
     }
 
+    unsafe fn find_any<const N: usize>(&mut self, chars: [u8; N]) -> bool {
+        self.mask_and_find(|chunk| {
+            let mut cmp = _mm_setzero_si128();
+            for ch in chars {
+                cmp = _mm_or_si128(cmp, _mm_cmpeq_epi8(chunk, _mm_set1_epi8(ch as i8)));
+            }
+            _mm_movemask_epi8(cmp) // 3 cycles per char
+        })
+    }
+
+    /// Skips a string literal starting at `self.ptr`, stops at the closing `delimiter`
+    /// or at the end of the line
+    unsafe fn skip_literal(&mut self, delimiter: u8, config: &Config) {
+        // consume the opening delimiter
+        self.ptr = self.ptr.add(1);
+
+        let escape = config.string_escape.unwrap_or(b'\n');
+        while self.find_any([b'\n', delimiter, escape]) {
+            let ch = *self.ptr;
+            if ch == b'\n' {
+                // unterminated literal
+                return;
+            }
+
+            self.ptr = self.ptr.add(1);
+            if ch == delimiter {
+                return;
+            }
+
+            // escaped char, unless it's the end of the line
+            if self.ptr < self.ptr_end && *self.ptr != b'\n' {
+                self.ptr = self.ptr.add(1);
+            }
+        }
+    }
+
+    /// Moves to the end of the line keeping track of the multi-line comments
+    unsafe fn skip_line(&mut self, config: &Config) {
+        let begin = config.comment_begin.as_bytes();
+        if begin.is_empty() {
+            // multi-line comments are disabled
+            if !self.find(b'\n') {
+                self.ptr = self.ptr_end;
+            }
+            return;
+        }
+
+        let end = config.comment_end.as_bytes();
+        let comment = config.comment.as_bytes();
+        let delimiters = config.string_delimiters.as_bytes();
+        let delimiter = |i: usize| delimiters.get(i).copied().unwrap_or(b'\n');
+        let chars = [
+            b'\n',
+            begin[0],
+            end[0],
+            comment.first().copied().unwrap_or(b'\n'),
+            delimiter(0),
+            delimiter(1),
+            delimiter(2),
+            delimiter(3),
+        ];
+
+        while self.find_any(chars) {
+            if *self.ptr == b'\n' {
+                return;
+            }
+
+            let chunk = load(self.ptr, self.ptr_end); // 6 cycles
+            let len = self.ptr_end.offset_from(self.ptr) as usize;
+            if self.comment_depth == 0 {
+                if start_with(chunk, len, self.patterns.comment_begin) {
+                    self.comment_depth = 1;
+                    self.ptr = self.ptr.add(begin.len());
+                    continue;
+                }
+                if !comment.is_empty() && start_with(chunk, len, self.patterns.comment) {
+                    // single line comment, nothing else to look for
+                    if !self.find(b'\n') {
+                        self.ptr = self.ptr_end;
+                    }
+                    return;
+                }
+                let ch = *self.ptr;
+                if delimiters.contains(&ch) {
+                    // comments inside string literals are ignored
+                    self.skip_literal(ch, config);
+                    continue;
+                }
+            } else {
+                if start_with(chunk, len, self.patterns.comment_end) {
+                    self.comment_depth -= 1;
+                    self.ptr = self.ptr.add(end.len());
+                    continue;
+                }
+                if config.nested_comments && start_with(chunk, len, self.patterns.comment_begin) {
+                    self.comment_depth += 1;
+                    self.ptr = self.ptr.add(begin.len());
+                    continue;
+                }
+            }
+
+            self.ptr = self.ptr.add(1);
+        }
+    }
+
+    /// Checks if `ptr` is at a word boundary, that is a white space, the begining of a comment,
+    /// a `(` or the end of the line, after the keyword of `directive` the char that can start
+    /// its argument is also accepted, like in `#if!A` or `#include"a"`
+    unsafe fn is_boundary(
+        &self,
+        ptr: *const u8,
+        directive: Option<Directive>,
+        config: &Config,
+    ) -> bool {
+        if ptr >= self.ptr_end {
+            return true;
+        }
+
+        match (*ptr, directive) {
+            (b' ' | b'\t' | b'\r' | b'\n' | b'(', _) => true,
+            (b'!', Some(Directive::If | Directive::Elif)) => true,
+            (ch, Some(Directive::Include)) if ch == config.include_begin => true,
+            _ => {
+                let chunk = load(ptr, self.ptr_end); // 6 cycles
+                let len = self.ptr_end.offset_from(ptr) as usize;
+                let Patterns {
+                    comment,
+                    comment_begin,
+                    ..
+                } = self.patterns;
+                (comment.len != 0 && start_with(chunk, len, comment))
+                    || (comment_begin.len != 0 && start_with(chunk, len, comment_begin))
+            }
+        }
+    }
+
     #[inline(always)]
     fn enter(&mut self) {
         self.line_count += 1;
@@ -121,11 +372,16 @@ impl Parser {
     }
 
     #[inline(always)]
-    unsafe fn char_pos(&self) -> usize {
-        str_from_range(self.line_ptr, self.ptr).chars().count()
+    /// Position of `ptr` reported in the errors
+    unsafe fn position(&self) -> Position<'p> {
+        Position {
+            path: self.path,
+            line: self.line_count,
+            column: str_from_range(self.line_ptr, self.ptr).chars().count(),
+        }
     }
 
-    unsafe fn exp<'a, 'b>(&mut self, config: &'b Config) -> Exp<'a> {
+    unsafe fn exp<'a>(&mut self, config: &Config) -> Exp<'a> {
         // copied from exp.rs, but modified to support comments and newline
         //
         // uses the shunting yard algorithm
@@ -150,30 +406,45 @@ impl Parser {
         let comment_char = config
             .comment
             .as_bytes()
-            .get(0)
+            .first()
             .copied()
             .unwrap_or_default() as i8;
-        let comment_rem = config.comment.as_bytes().get(1..).unwrap_or_default();
+
+        // '\n' is used when multi-line comments are disabled since it already breaks the expression
+        let comment_begin_char = config
+            .comment_begin
+            .as_bytes()
+            .first()
+            .copied()
+            .unwrap_or(b'\n') as i8;
+
+        // string literals aren't allowed inside expressions, '\n' is used to fill the unused slots
+        let delimiters = config.string_delimiters.as_bytes();
+        let delimiter = |i: usize| delimiters.get(i).copied().unwrap_or(b'\n') as i8;
+        let delimiters = [delimiter(0), delimiter(1), delimiter(2), delimiter(3)];
 
         let mut token_ptr = self.ptr;
 
+        // used to validate the expression, operands and binary operators must alternate
+        let mut expect_operand = true;
+
         let break_ch = _mm_set_epi8(
-            0,
-            0,
-            0,
-            0,
-            0,
-            comment_char, // 10
-            b'\0' as i8,  // 9
-            b'\r' as i8,  // 8
-            b'\n' as i8,  // 7
-            b'\t' as i8,  // 6
-            b' ' as i8,   // 5
-            b'!' as i8,   // 4
-            b'&' as i8,   // 3
-            b'(' as i8,   // 2
-            b')' as i8,   // 1
-            b'|' as i8,   // 0
+            delimiters[3],      // 15
+            delimiters[2],      // 14
+            delimiters[1],      // 13
+            delimiters[0],      // 12
+            comment_begin_char, // 11
+            comment_char,       // 10
+            b'\0' as i8,        // 9
+            b'\r' as i8,        // 8
+            b'\n' as i8,        // 7
+            b'\t' as i8,        // 6
+            b' ' as i8,         // 5
+            b'!' as i8,         // 4
+            b'&' as i8,         // 3
+            b'(' as i8,         // 2
+            b')' as i8,         // 1
+            b'|' as i8,         // 0
         );
 
         loop {
@@ -190,7 +461,7 @@ impl Parser {
                 unsafe { _mm_movemask_epi8(_mm_cmpeq_epi8(_mm_set1_epi8(ch as _), break_ch)) };
 
             if break_mask != 0 {
-                if break_mask & 0b1111_1011_0110_0000 != 0 {
+                if break_mask & 0b0000_0011_0110_0000 != 0 {
                     // accept and skip
                     token_ptr = self.ptr;
                     continue;
@@ -202,12 +473,27 @@ impl Parser {
                     break;
                 }
 
+                if break_mask & 0b1111_0000_0000_0000 != 0 {
+                    panic!("unexpected string literal {}", self.position());
+                }
+
                 if break_mask & 0b0000_0100_0000_0000 != 0 {
                     // check if is a comment
                     // todo: usually just less than 4 chars, maybe just use a default `str::starts_with`
-                    let chunk = _mm_loadu_si128(self.ptr as *const _); // 6 cycles
+                    let chunk = load(self.ptr, self.ptr_end); // 6 cycles
+                    let len = self.ptr_end.offset_from(self.ptr) as usize;
+                    if start_with(chunk, len, self.patterns.comment_rem) {
+                        // roll back and break
+                        self.ptr = self.ptr.sub(1);
+                        break;
+                    }
+                }
+
+                if break_mask & 0b0000_1000_0000_0000 != 0 {
+                    // check if is a multi-line comment
+                    let chunk = load(self.ptr, self.ptr_end); // 6 cycles
                     let len = self.ptr_end.offset_from(self.ptr) as usize;
-                    if start_with(chunk, len, comment_rem) {
+                    if start_with(chunk, len, self.patterns.comment_begin_rem) {
                         // roll back and break
                         self.ptr = self.ptr.sub(1);
                         break;
@@ -215,12 +501,18 @@ impl Parser {
                 }
 
                 if break_mask & 0b0000_0100 != 0 {
+                    if !expect_operand {
+                        panic!("expecting operator {}", self.position());
+                    }
                     token_ptr = self.ptr; // accept the token
                     stack.push((Token::LParen, self.ptr));
                     continue;
                 }
 
                 if break_mask & 0b0000_0010 != 0 {
+                    if expect_operand {
+                        panic!("expecting operand {}", self.position());
+                    }
                     token_ptr = self.ptr; // accept the token
                     loop {
                         if let Some((token, _)) = stack.pop() {
@@ -230,7 +522,7 @@ impl Parser {
                                 break;
                             }
                         } else {
-                            panic!("unmached `)` {}:{}", self.line_count, self.char_pos());
+                            panic!("unmached `)` {}", self.position());
                         }
                     }
                     continue;
@@ -240,14 +532,14 @@ impl Parser {
                 if break_mask & 0b0000_1000 != 0 {
                     // and
                     if self.ptr >= self.ptr_end || unsafe { *self.ptr } != b'&' {
-                        panic!("expecting `&&` {}:{}", self.line_count, self.char_pos());
+                        panic!("expecting `&&` {}", self.position());
                     }
                     self.ptr = self.ptr.add(1);
                     op0 = Token::And;
                 } else if break_mask & 0b0000_0001 != 0 {
                     // or
                     if self.ptr >= self.ptr_end || unsafe { *self.ptr } != b'|' {
-                        panic!("expecting `||` {}:{}", self.line_count, self.char_pos());
+                        panic!("expecting `||` {}", self.position());
                     }
                     self.ptr = self.ptr.add(1);
                     op0 = Token::Or;
@@ -258,11 +550,19 @@ impl Parser {
                     op0 = Token::Noop;
                 }
                 if op0 != Token::Noop {
+                    if op0 != Token::Not {
+                        // binary operator
+                        if expect_operand {
+                            panic!("expecting operand {}", self.position());
+                        }
+                        expect_operand = true;
+                    }
                     token_ptr = self.ptr; // accept the token
                     loop {
                         let pre0 = unsafe { *PRECEDENCE.get_unchecked(op0 as usize) };
                         if let Some(&(op1, _)) = stack.last() {
-                            if op1 == Token::LParen {
+                            // a prefix `!` is right associative and must not pop the operators before it
+                            if op1 == Token::LParen || (op0 == Token::Not && expect_operand) {
                                 break;
                             }
                             let pre1 = unsafe { *PRECEDENCE.get_unchecked(op1 as usize) };
@@ -288,8 +588,8 @@ impl Parser {
                     // not very good vor short variable names
                     // ignore spaces
                     let break_mask = unsafe {
-                        let chunk = _mm_loadu_si128(self.ptr as *const __m128i); // 6 cycles
-                        _mm_movemask_epi8(_mm_or_si128(
+                        let chunk = load(self.ptr, self.ptr_end); // 6 cycles
+                        let break_mask = _mm_movemask_epi8(_mm_or_si128(
                             _mm_or_si128(
                                 _mm_or_si128(
                                     _mm_or_si128(
@@ -313,11 +613,26 @@ impl Parser {
                                 ),
                                 _mm_or_si128(
                                     _mm_cmpeq_epi8(chunk, _mm_set1_epi8(b'|' as i8)),
-                                    _mm_cmpeq_epi8(chunk, _mm_set1_epi8(comment_char)),
+                                    _mm_or_si128(
+                                        _mm_cmpeq_epi8(chunk, _mm_set1_epi8(comment_char)),
+                                        _mm_cmpeq_epi8(chunk, _mm_set1_epi8(comment_begin_char)),
+                                    ),
                                 ),
                             ),
-                        )) // 19 + 3 cycles
+                        ));
+                        let delimiters_mask = _mm_movemask_epi8(_mm_or_si128(
+                            _mm_or_si128(
+                                _mm_cmpeq_epi8(chunk, _mm_set1_epi8(delimiters[0])),
+                                _mm_cmpeq_epi8(chunk, _mm_set1_epi8(delimiters[1])),
+                            ),
+                            _mm_or_si128(
+                                _mm_cmpeq_epi8(chunk, _mm_set1_epi8(delimiters[2])),
+                                _mm_cmpeq_epi8(chunk, _mm_set1_epi8(delimiters[3])),
+                            ),
+                        ));
+                        break_mask | delimiters_mask // 33 + 3 cycles
                     };
+
                     if break_mask != 0 {
                         // found something
                         let break_offset = break_mask.trailing_zeros() as usize;
@@ -336,6 +651,11 @@ impl Parser {
                 }
 
                 // safety: str slice respect the utf8 chars continuation bytes, because it will only split in ascii chars
+                if !expect_operand {
+                    panic!("expecting operator {}", self.position());
+                }
+                expect_operand = false;
+
                 let token = unsafe { str_from_range(token_ptr, self.ptr) };
                 ops.push(Op::Var(token));
 
@@ -346,12 +666,14 @@ impl Parser {
 
         while let Some((token, _)) = stack.pop() {
             if token == Token::LParen {
-                panic!("unmached `(` {}:{}", self.line_count, self.char_pos());
+                panic!("unmached `(` {}", self.position());
             }
             ops.push(unsafe { *OPERATORS.get_unchecked(token as usize) });
         }
 
-        // todo: check if the expression is valid or not
+        if expect_operand {
+            panic!("expecting operand {}", self.position());
+        }
 
         Exp { ops }
     }
@@ -362,22 +684,19 @@ impl Parser {
         config: &'b Config,
         mut f: impl FnMut(Line<'a>),
     ) {
-        // make some assertions about the lenght of the comments
-        assert!(
-            config.comment.len() <= 16,
-            "`comment` \"{}\" exceeded 16 chars limit",
-            config.comment
-        );
-
         self.ptr = data.as_ptr();
         self.ptr_end = self.ptr.add(data.len());
 
         self.line_count = 1;
         self.line_ptr = self.ptr;
+        self.comment_depth = 0;
 
         while self.ptr < self.ptr_end {
             if !self.ignore_space() {
-                // nothing left
+                // nothing left but a line made of white spaces
+                if self.line_ptr < self.ptr_end {
+                    (f)(Line::Code(str_from_raw_parts(self.line_ptr, 0)));
+                }
                 break;
             }
 
@@ -395,43 +714,48 @@ impl Parser {
                 continue;
             }
 
-            if ch == config.special_char {
-                // directive
+            if ch == config.special_char && self.comment_depth == 0 {
+                // directive, unless inside a multi-line comment
                 self.ptr = self.ptr.add(1);
 
                 let len = self.ptr_end.offset_from(self.ptr) as usize;
                 if len != 0 {
-                    let chunk = _mm_loadu_si128(self.ptr as *const _); // 6 cycles
+                    let chunk = load(self.ptr, self.ptr_end); // 6 cycles
+
+                    // keywords must be followed by a word boundary, so `#ifdef` isn't taken as `#if`,
+                    // in case of many matches the longest keyword wins
+                    let mut directive = None;
+                    let mut keyword_len = 0;
+                    for &(keyword, kind) in &self.patterns.keywords {
+                        if keyword.len > keyword_len
+                            && start_with(chunk, len, keyword)
+                            && self.is_boundary(self.ptr.add(keyword.len), Some(kind), config)
+                        {
+                            directive = Some(kind);
+                            keyword_len = keyword.len;
+                        }
+                    }
+                    self.ptr = self.ptr.add(keyword_len);
 
-                    if start_with(chunk, len, b"if") {
-                        self.ptr = self.ptr.add(b"if".len());
+                    if directive == Some(Directive::If) {
                         (f)(Line::If(self.exp(config)));
-                    } else if start_with(chunk, len, b"elif") {
-                        self.ptr = self.ptr.add(b"elif".len());
+                    } else if directive == Some(Directive::Elif) {
                         (f)(Line::Elif(self.exp(config)));
-                    } else if start_with(chunk, len, b"else") {
-                        self.ptr = self.ptr.add(b"else".len());
+                    } else if directive == Some(Directive::Else) {
                         (f)(Line::Else);
-                    } else if start_with(chunk, len, b"endif") {
-                        self.ptr = self.ptr.add(b"endif".len());
+                    } else if directive == Some(Directive::Endif) {
                         (f)(Line::Endif);
-                    } else if start_with(chunk, len, b"undef") {
-                        self.ptr = self.ptr.add(b"undef".len());
-
+                    } else if directive == Some(Directive::Undef) {
                         // todo: should "undef  \n" case be handled?
                         self.ignore_space();
 
                         let def_ptr = self.ptr;
 
                         // todo: usually just less than 4 chars, maybe just use a default `str::starts_with`
-                        let chunk = _mm_loadu_si128(self.ptr as *const _); // 6 cycles
+                        let chunk = load(self.ptr, self.ptr_end); // 6 cycles
                         let len = self.ptr_end.offset_from(self.ptr) as usize;
-                        if start_with(chunk, len, config.comment.as_bytes()) {
-                            panic!(
-                                "missing define name of `define` {}:{}",
-                                self.line_count,
-                                self.char_pos()
-                            );
+                        if start_with(chunk, len, self.patterns.comment) {
+                            panic!("missing define name of `define` {}", self.position());
                         }
 
                         if !self.find_space_or_enter() {
@@ -439,23 +763,17 @@ impl Parser {
                         }
 
                         (f)(Line::Undef(str_from_range(def_ptr, self.ptr)));
-                    } else if start_with(chunk, len, b"define") {
-                        self.ptr = self.ptr.add(b"define".len());
-
+                    } else if directive == Some(Directive::Define) {
                         // todo: should "undef  \n" case be handled?
                         self.ignore_space();
 
                         let def_ptr = self.ptr;
 
                         // todo: usually just less than 4 chars, maybe just use a default `str::starts_with`
-                        let chunk = _mm_loadu_si128(self.ptr as *const _); // 6 cycles
+                        let chunk = load(self.ptr, self.ptr_end); // 6 cycles
                         let len = self.ptr_end.offset_from(self.ptr) as usize;
-                        if start_with(chunk, len, config.comment.as_bytes()) {
-                            panic!(
-                                "missing define name of `define` {}:{}",
-                                self.line_count,
-                                self.char_pos()
-                            );
+                        if start_with(chunk, len, self.patterns.comment) {
+                            panic!("missing define name of `define` {}", self.position());
                         }
 
                         if !self.find_space_or_enter() {
@@ -463,18 +781,15 @@ impl Parser {
                         }
 
                         (f)(Line::Def(str_from_range(def_ptr, self.ptr)));
-                    } else if start_with(chunk, len, b"include") {
-                        self.ptr = self.ptr.add(b"include".len());
-
+                    } else if directive == Some(Directive::Include) {
                         self.ignore_space();
 
                         // assert the char is '\"'
                         if self.ptr >= self.ptr_end || *self.ptr != config.include_begin {
                             panic!(
-                                "missing start delimiter '{:?}' of `include` {}:{}",
+                                "missing start delimiter '{:?}' of `include` {}",
                                 char::from_u32_unchecked(config.include_begin as _),
-                                self.line_count,
-                                self.char_pos()
+                                self.position()
                             );
                         }
 
@@ -488,10 +803,9 @@ impl Parser {
                         if !self.find(config.include_end) {
                             // assert the char is \"
                             panic!(
-                                "missing end delimiter '{:?}' of `include` {}:{}",
-                                char::from_u32_unchecked(config.include_begin as _),
-                                self.line_count,
-                                self.char_pos()
+                                "missing end delimiter '{:?}' of `include` {}",
+                                char::from_u32_unchecked(config.include_end as _),
+                                self.position()
                             );
                         }
 
@@ -501,13 +815,33 @@ impl Parser {
                         // consume delimiter
                         self.ptr = self.ptr.add(1);
                     } else {
-                        // unknown directives will be treated as lines of code
-                        if !self.find(b'\n') {
-                            // return the remaning of the the data without going out of bounds
-                            self.ptr = self.ptr_end
+                        // unknown directive, names are made of ASCII alphanumeric chars and `_`
+                        let name_ptr = self.ptr;
+                        while self.ptr < self.ptr_end
+                            && ((*self.ptr).is_ascii_alphanumeric() || *self.ptr == b'_')
+                        {
+                            self.ptr = self.ptr.add(1);
                         }
 
-                        (f)(Line::Code(line(self.line_ptr, self.ptr)));
+                        if self.ptr == name_ptr || !self.is_boundary(self.ptr, None, config) {
+                            // not a directive at all like `#[derive(Debug)]`, treated as a line of code
+                            self.skip_line(config);
+
+                            (f)(Line::Code(line(self.line_ptr, self.ptr)));
+                        } else {
+                            let name = str_from_range(name_ptr, self.ptr);
+
+                            self.ignore_space();
+                            let rest_ptr = self.ptr;
+
+                            self.skip_line(config);
+
+                            (f)(Line::Unknown {
+                                line: line(self.line_ptr, self.ptr),
+                                name,
+                                rest: line(rest_ptr, self.ptr),
+                            });
+                        }
 
                         // skip '\n'
                         self.ptr = self.ptr.add(1);
@@ -516,10 +850,14 @@ impl Parser {
 
                         continue;
                     }
+                } else {
+                    // a lone special char at the end, a line of code like when a line ending follows it
+                    (f)(Line::Code(line(self.line_ptr, self.ptr)));
+                    break;
+                }
 
-                    if self.ptr >= self.ptr_end {
-                        break;
-                    }
+                if self.ptr >= self.ptr_end {
+                    break;
                 }
 
                 // account for "\r\n" line end format, this is important to avoid output extra `Line::Rem` events
@@ -536,10 +874,7 @@ impl Parser {
 
                     let rem_ptr = self.ptr;
 
-                    if !self.find(b'\n') {
-                        // return the remaning of the the data without going out of bounds
-                        self.ptr = self.ptr_end;
-                    }
+                    self.skip_line(config);
 
                     (f)(Line::Rem(line(rem_ptr, self.ptr)));
                 }
@@ -552,10 +887,7 @@ impl Parser {
                 continue;
             }
 
-            if !self.find(b'\n') {
-                // return the remaning of the the data without going out of bounds
-                self.ptr = self.ptr_end
-            }
+            self.skip_line(config);
 
             (f)(Line::Code(line(self.line_ptr, self.ptr)));
 
@@ -568,8 +900,20 @@ impl Parser {
 }
 
 pub fn parse_file<'a>(input: &'a str, config: &Config, f: impl FnMut(Line<'a>)) {
-    let mut parser = Parser::new();
-    unsafe { parser.parse(input, config, f) };
+    parse_file_at(input, "", config, f);
+}
+
+/// Like [`parse_file`] but the errors are reported with `path`
+pub(crate) fn parse_file_at<'a>(
+    input: &'a str,
+    path: &str,
+    config: &Config,
+    f: impl FnMut(Line<'a>),
+) {
+    unsafe {
+        let mut parser = Parser::new(path, config);
+        parser.parse(input, config, f);
+    }
 }
 
 pub fn parse_exp<'a>(exp: &'a str) -> Result<Exp<'a>, exp::Error> {
@@ -596,6 +940,9 @@ pub fn parse_exp<'a>(exp: &'a str) -> Result<Exp<'a>, exp::Error> {
     let mut offset = 0;
     let mut token_offset = 0;
 
+    // used to validate the expression, operands and binary operators must alternate
+    let mut expect_operand = true;
+
     let break_ch = unsafe {
         _mm_set_epi8(
             0,
@@ -638,12 +985,26 @@ pub fn parse_exp<'a>(exp: &'a str) -> Result<Exp<'a>, exp::Error> {
             }
 
             if break_mask & 0b0000_0100 != 0 {
+                if !expect_operand {
+                    return Err(exp::Error {
+                        offset: offset - 1,
+                        len: 1,
+                        message: Cow::borrowed("expecting operator"),
+                    });
+                }
                 token_offset = offset; // accept the token
                 stack.push((Token::LParen, offset));
                 continue;
             }
 
             if break_mask & 0b0000_0010 != 0 {
+                if expect_operand {
+                    return Err(exp::Error {
+                        offset: offset - 1,
+                        len: 1,
+                        message: Cow::borrowed("expecting operand"),
+                    });
+                }
                 token_offset = offset; // accept the token
                 loop {
                     if let Some((token, _)) = stack.pop() {
@@ -693,11 +1054,23 @@ pub fn parse_exp<'a>(exp: &'a str) -> Result<Exp<'a>, exp::Error> {
                 op0 = Token::Noop;
             }
             if op0 != Token::Noop {
+                if op0 != Token::Not {
+                    // binary operator
+                    if expect_operand {
+                        return Err(exp::Error {
+                            offset: offset - 2,
+                            len: 2,
+                            message: Cow::borrowed("expecting operand"),
+                        });
+                    }
+                    expect_operand = true;
+                }
                 token_offset = offset; // accept the token
                 loop {
                     let pre0 = unsafe { *PRECEDENCE.get_unchecked(op0 as usize) };
                     if let Some(&(op1, _)) = stack.last() {
-                        if op1 == Token::LParen {
+                        // a prefix `!` is right associative and must not pop the operators before it
+                        if op1 == Token::LParen || (op0 == Token::Not && expect_operand) {
                             break;
                         }
                         let pre1 = unsafe { *PRECEDENCE.get_unchecked(op1 as usize) };
@@ -723,8 +1096,8 @@ pub fn parse_exp<'a>(exp: &'a str) -> Result<Exp<'a>, exp::Error> {
                 // not very good vor short variable names
                 // ignore spaces
                 let break_mask = unsafe {
-                    let chunk =
-                        _mm_loadu_si128(data.get_unchecked(offset) as *const u8 as *const __m128i); // 6 cycles
+                    let ptr = data.as_ptr();
+                    let chunk = load(ptr.add(offset), ptr.add(data.len())); // 6 cycles
                     _mm_movemask_epi8(_mm_or_si128(
                         _mm_or_si128(
                             _mm_or_si128(
@@ -763,6 +1136,15 @@ pub fn parse_exp<'a>(exp: &'a str) -> Result<Exp<'a>, exp::Error> {
             }
 
             // safety: str slice respect the utf8 chars continuation bytes, because it will only split in ascii chars
+            if !expect_operand {
+                return Err(exp::Error {
+                    offset: token_offset,
+                    len: offset - token_offset,
+                    message: Cow::borrowed("expecting operator"),
+                });
+            }
+            expect_operand = false;
+
             let token = unsafe {
                 str_from_raw_parts(data.get_unchecked(token_offset), offset - token_offset)
             };
@@ -784,7 +1166,13 @@ pub fn parse_exp<'a>(exp: &'a str) -> Result<Exp<'a>, exp::Error> {
         ops.push(unsafe { *OPERATORS.get_unchecked(token as usize) });
     }
 
-    // todo: check if the expression is valid or not
+    if expect_operand {
+        return Err(exp::Error {
+            offset: data.len(),
+            len: 0,
+            message: Cow::borrowed("expecting operand"),
+        });
+    }
 
     Ok(Exp { ops })
 }
@@ -849,4 +1237,208 @@ mod tests {
             Line::Code("}"),
         ]);
     }
+
+    #[test]
+    fn multiline_comments() {
+        fn parse<'a>(text: &'a str, config: &Config) -> Vec<Line<'a>> {
+            let mut lines = vec![];
+            parse_file(text, config, |line| lines.push(line));
+            lines
+        }
+
+        let text = "/*#if A\n#endif*/\n#if B /* note\n#else */\n// /*\n#endif";
+        assert_eq!(
+            parse(text, &Config::default()),
+            &[
+                Line::Code("/*#if A"),
+                Line::Code("#endif*/"),
+                Line::If(Exp::from_str("B").unwrap()),
+                Line::Rem("/* note"),
+                Line::Code("#else */"),
+                Line::Code("// /*"),
+                Line::Endif,
+            ]
+        );
+
+        let text = "/* /* */\n#if A\n*/\n#if B";
+        assert_eq!(
+            parse(text, &Config::default()),
+            &[
+                Line::Code("/* /* */"),
+                Line::If(Exp::from_str("A").unwrap()),
+                Line::Code("*/"),
+                Line::If(Exp::from_str("B").unwrap()),
+            ]
+        );
+
+        let config = Config {
+            nested_comments: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse(text, &config),
+            &[
+                Line::Code("/* /* */"),
+                Line::Code("#if A"),
+                Line::Code("*/"),
+                Line::If(Exp::from_str("B").unwrap()),
+            ]
+        );
+
+        let config = Config {
+            comment_begin: "".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("/*\n#if A\n*/", &config),
+            &[
+                Line::Code("/*"),
+                Line::If(Exp::from_str("A").unwrap()),
+                Line::Code("*/"),
+            ]
+        );
+    }
+
+    #[test]
+    fn string_literals() {
+        fn parse<'a>(text: &'a str, config: &Config) -> Vec<Line<'a>> {
+            let mut lines = vec![];
+            parse_file(text, config, |line| lines.push(line));
+            lines
+        }
+
+        let text = "var url = \"http://*\"; // '/*'\n#if A\nvar s = \"\\\"/*\", c = '\"';\n#define B \"/*\"\n#endif";
+        assert_eq!(
+            parse(text, &Config::default()),
+            &[
+                Line::Code("var url = \"http://*\"; // '/*'"),
+                Line::If(Exp::from_str("A").unwrap()),
+                Line::Code("var s = \"\\\"/*\", c = '\"';"),
+                Line::Def("B"),
+                Line::Rem(" \"/*\""),
+                Line::Endif,
+            ]
+        );
+
+        // unterminated literals end with the line
+        let text = "x = \"/*\n#if A";
+        assert_eq!(
+            parse(text, &Config::default()),
+            &[
+                Line::Code("x = \"/*"),
+                Line::If(Exp::from_str("A").unwrap())
+            ]
+        );
+
+        let config = Config {
+            string_delimiters: "".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("x = \"/*\";\n#if A", &config),
+            &[Line::Code("x = \"/*\";"), Line::Code("#if A")]
+        );
+    }
+
+    #[test]
+    fn custom_directives() {
+        let mut config = Config {
+            special_char: b'@',
+            ..Default::default()
+        };
+        config
+            .directives
+            .retain(|(_, kind)| *kind != Directive::Include);
+        config
+            .directives
+            .push(("import".into(), Directive::Include));
+        config.directives.push(("elseif".into(), Directive::Elif));
+        config.directives.push(("elsif".into(), Directive::Elif));
+
+        let text =
+            "@import \"a.wgsl\"\n@if A\n@elseif B\n@elsif C\n@else\n@endif\n@include \"b.wgsl\"";
+        let mut lines = vec![];
+        parse_file(text, &config, |line| lines.push(line));
+        assert_eq!(
+            lines,
+            &[
+                Line::Inc("a.wgsl"),
+                Line::If(Exp::from_str("A").unwrap()),
+                Line::Elif(Exp::from_str("B").unwrap()),
+                Line::Elif(Exp::from_str("C").unwrap()),
+                Line::Else,
+                Line::Endif,
+                Line::Unknown {
+                    line: "@include \"b.wgsl\"",
+                    name: "include",
+                    rest: "\"b.wgsl\"",
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn exact_keywords() {
+        let text = "#ifdef A\n#if(B)\n#include_once \"a\"\n#else_comment\n#endif// done\n  #pragma once \r\n#version\n#[derive(Debug)]\n#if! C\n#elif!D\n#include\"b\"";
+        let mut lines = vec![];
+        parse_file(text, &Config::default(), |line| lines.push(line));
+        assert_eq!(
+            lines,
+            &[
+                Line::Unknown {
+                    line: "#ifdef A",
+                    name: "ifdef",
+                    rest: "A",
+                },
+                Line::If(Exp::from_str("B").unwrap()),
+                Line::Unknown {
+                    line: "#include_once \"a\"",
+                    name: "include_once",
+                    rest: "\"a\"",
+                },
+                Line::Unknown {
+                    line: "#else_comment",
+                    name: "else_comment",
+                    rest: "",
+                },
+                Line::Endif,
+                Line::Rem("// done"),
+                Line::Unknown {
+                    line: "  #pragma once ",
+                    name: "pragma",
+                    rest: "once ",
+                },
+                Line::Unknown {
+                    line: "#version",
+                    name: "version",
+                    rest: "",
+                },
+                Line::Code("#[derive(Debug)]"),
+                Line::If(Exp::from_str("!C").unwrap()),
+                Line::Elif(Exp::from_str("!D").unwrap()),
+                Line::Inc("b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn special_char_at_the_end() {
+        let config = Config::default();
+        for (text, expected) in [
+            ("a\n#", &[Line::Code("a"), Line::Code("#")][..]),
+            ("a\n  #", &[Line::Code("a"), Line::Code("  #")]),
+            ("#", &[Line::Code("#")]),
+            ("#\n", &[Line::Code("#")]),
+        ] {
+            let mut lines = vec![];
+            parse_file(text, &config, |line| lines.push(line));
+            assert_eq!(lines, expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected string literal")]
+    fn string_literal_in_exp() {
+        parse_file("#if A && \"/*\"", &Config::default(), |_| {});
+    }
 }